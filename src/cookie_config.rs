@@ -0,0 +1,111 @@
+use cookie::{Cookie, CookieBuilder, SameSite};
+use time;
+
+/// Attributes of the session cookie
+#[derive(Clone, Debug)]
+pub struct CookieConfig {
+    pub name: String,
+    /// domain the cookie is sent to including subdomains, the host of the request if None
+    pub domain: Option<String>,
+    pub path: String,
+    /// only send the cookie over HTTPS
+    pub secure: bool,
+    pub same_site: SameSite,
+}
+
+impl CookieConfig {
+    fn builder(&self, name: String, value: String, path: String) -> CookieBuilder {
+        let builder = Cookie::build(name, value)
+            .http_only(true)
+            .path(path)
+            .secure(self.secure)
+            .same_site(self.same_site);
+        match self.domain {
+            Some(ref domain) => builder.domain(domain.clone()),
+            None => builder,
+        }
+    }
+
+    /// Cookie with `value`, kept by the browser for `max_age` seconds
+    pub fn cookie(&self, value: String, max_age: u64) -> Cookie<'static> {
+        self.builder(self.name.clone(), value, self.path.clone())
+            .max_age(time::Duration::seconds(max_age as i64))
+            .finish()
+    }
+
+    /// Cookie that makes the browser delete the cookie
+    pub fn removal(&self) -> Cookie<'static> {
+        self.builder(self.name.clone(), String::new(), self.path.clone())
+            .expires(time::at_utc(time::Timespec::new(0, 0)))
+            .finish()
+    }
+
+    /// Name of the cookie carrying the CSRF token
+    pub fn csrf_name(&self) -> String {
+        format!("{}_csrf", self.name)
+    }
+
+    /// Cookie with the CSRF token, kept until the browser is closed. It is sent for every
+    /// path, the forms post to the auth location whatever the path of the session cookie.
+    pub fn csrf_cookie(&self, token: String) -> Cookie<'static> {
+        self.builder(self.csrf_name(), token, "/".to_string()).finish()
+    }
+}
+
+pub fn parse_same_site(s: &str) -> Result<SameSite, String> {
+    match s.to_lowercase().as_str() {
+        "strict" => Ok(SameSite::Strict),
+        "lax" => Ok(SameSite::Lax),
+        // cookie 0.11 writes no attribute for SameSite::None, browsers would fall back to lax
+        _ => Err(format!("unknown SameSite value '{}', expected strict or lax", s)),
+    }
+}
+
+/// Cookie names are tokens in the sense of RFC 7230
+pub fn parse_cookie_name(s: &str) -> Result<String, String> {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if s.is_empty() || !s.chars().all(is_tchar) {
+        return Err(format!("invalid cookie name '{}'", s));
+    }
+    Ok(s.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cookie_attributes() {
+        let mut config = CookieConfig {
+            name: "totp_cookie".to_string(),
+            domain: None,
+            path: "/".to_string(),
+            secure: false,
+            same_site: SameSite::Lax,
+        };
+        assert_eq!(config.cookie("v".to_string(), 60).to_string(),
+                   "totp_cookie=v; HttpOnly; SameSite=Lax; Path=/; Max-Age=60");
+
+        config.name = "sso".to_string();
+        config.domain = Some("example.org".to_string());
+        config.secure = true;
+        config.same_site = SameSite::Strict;
+        assert_eq!(config.cookie("v".to_string(), 60).to_string(),
+                   "sso=v; HttpOnly; Secure; SameSite=Strict; Path=/; Domain=example.org; Max-Age=60");
+        assert!(config.removal().to_string().starts_with("sso=; HttpOnly; Secure; SameSite=Strict; Path=/; Domain=example.org; Expires="));
+
+        config.path = "/app".to_string();
+        assert_eq!(config.cookie("v".to_string(), 60).path(), Some("/app"));
+        assert_eq!(config.csrf_cookie("t".to_string()).path(), Some("/"));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_same_site("Lax"), Ok(SameSite::Lax));
+        assert!(parse_same_site("sometimes").is_err());
+        assert!(parse_same_site("none").is_err());
+        assert!(parse_cookie_name("app_1.session").is_ok());
+        assert!(parse_cookie_name("a=b").is_err());
+        assert!(parse_cookie_name("").is_err());
+    }
+}
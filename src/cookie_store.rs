@@ -1,14 +1,18 @@
 use evmap;
 use std::sync::{Arc, Mutex, MutexGuard};
 use evmap::{WriteHandle, ReadHandle};
+use std::io;
+use std::path::Path;
 use std::time;
 use std::str;
 use std::hash;
+use std::net::IpAddr;
 
-use random;
-use random::Source;
+use journal::{Journal, Record};
 
-// cookie is a 64-byte printable-characters-only array
+use getrandom::getrandom;
+
+// cookie is a 64-byte printable-characters-only array, carrying 384 random bits
 pub struct CookieKey([u8; 64]);
 
 impl PartialEq for CookieKey {
@@ -39,9 +43,128 @@ impl ToString for CookieKey {
     }
 }
 
+/// Who logged in, as far as known
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Identity {
+    /// user name, or fingerprint of the secrets used to log in
+    pub account: String,
+    /// user that logged in, if users are configured
+    pub username: Option<String>,
+    /// groups of the user
+    pub groups: Vec<String>,
+}
+
+/// Properties of the client at login a session may be bound to
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Binding {
+    pub client_ip: Option<IpAddr>,
+    /// hex encoded SHA-256 of the User-Agent header
+    pub user_agent: Option<String>,
+}
+
+/// How long sessions are valid
+#[derive(Clone, Copy, Debug)]
+pub struct SessionLifetime {
+    /// seconds a session stays valid without being used
+    pub idle_timeout: u64,
+    /// seconds after login a session ends regardless of use
+    pub max_lifetime: u64,
+}
+
+/// The expiry of a session is only moved forward if it grows by at least this many
+/// seconds (or a tenth of the idle timeout if shorter), so not every request causes a write.
+const EXTEND_GRANULARITY_SECS: u64 = 60;
+
+impl SessionLifetime {
+    /// Expiry of a session issued at `issued` and last used at `now`
+    fn expiry(&self, issued: u64, now: u64) -> u64 {
+        (now + self.idle_timeout).min(issued + self.max_lifetime)
+    }
+}
+
+/// Authenticated session a cookie refers to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Session {
+    /// seconds since unix epoch when the session was created
+    pub issued: u64,
+    /// seconds since unix epoch until the session is valid
+    pub expires: u64,
+    pub identity: Identity,
+    pub binding: Binding,
+}
+
+impl Session {
+    pub fn new(identity: Identity, binding: Binding, lifetime: &SessionLifetime) -> Session {
+        let now = CookieStore::now_unix_epoch();
+        Session { issued: now, expires: lifetime.expiry(now, now), identity, binding }
+    }
+
+    /// The session with its expiry moved forward after being used now, None if it
+    /// would not change noticeably.
+    pub fn extended(&self, lifetime: &SessionLifetime) -> Option<Session> {
+        self.extended_at(lifetime, CookieStore::now_unix_epoch())
+    }
+
+    fn extended_at(&self, lifetime: &SessionLifetime, now: u64) -> Option<Session> {
+        let expires = lifetime.expiry(self.issued, now);
+        if expires < self.expires + EXTEND_GRANULARITY_SECS.min(lifetime.idle_timeout / 10) {
+            return None;
+        }
+        Some(Session { expires, ..self.clone() })
+    }
+
+    /// Key/value representation used to persist the session
+    pub fn to_record(&self) -> Vec<(&'static str, String)> {
+        let mut record = vec![("issued", self.issued.to_string()),
+                              ("expires", self.expires.to_string()),
+                              ("account", self.identity.account.clone())];
+        if let Some(ref username) = self.identity.username {
+            record.push(("user", username.clone()));
+        }
+        for group in self.identity.groups.iter() {
+            record.push(("group", group.clone()));
+        }
+        if let Some(ref client_ip) = self.binding.client_ip {
+            record.push(("ip", client_ip.to_string()));
+        }
+        if let Some(ref user_agent) = self.binding.user_agent {
+            record.push(("ua", user_agent.clone()));
+        }
+        record
+    }
+
+    pub fn from_record(record: &Record) -> Option<Session> {
+        let mut issued = None;
+        let mut expires = None;
+        let mut identity = Identity::default();
+        let mut binding = Binding::default();
+        for &(ref k, ref v) in record {
+            match k.as_str() {
+                "issued" => issued = v.parse().ok(),
+                "expires" => expires = v.parse().ok(),
+                "account" => identity.account = v.clone(),
+                "user" => identity.username = Some(v.clone()),
+                "group" => identity.groups.push(v.clone()),
+                "ip" => binding.client_ip = Some(v.parse().ok()?),
+                "ua" => binding.user_agent = Some(v.clone()),
+                _ => (),
+            }
+        }
+        Some(Session { issued: issued.unwrap_or(0), expires: expires?, identity, binding })
+    }
+}
+
+fn add_record<'a>(key: &'a str, fields: &'a [(&'a str, String)]) -> Vec<(&'a str, &'a str)> {
+    let mut record = vec![("op", "add"), ("key", key)];
+    record.extend(fields.iter().map(|&(k, ref v)| (k, v.as_str())));
+    record
+}
+
 pub struct CookieStore {
-    pub reader: ReadHandle<CookieKey, u64>,
-    pub writer: Arc<Mutex<WriteHandle<CookieKey, u64>>>,
+    pub reader: ReadHandle<CookieKey, Arc<Session>>,
+    pub writer: Arc<Mutex<WriteHandle<CookieKey, Arc<Session>>>>,
+    journal: Option<Arc<Mutex<Journal>>>,
+    max_sessions: Option<usize>,
 }
 
 pub fn to_cookie(data: &str) -> Option<CookieKey> {
@@ -54,8 +177,9 @@ pub fn to_cookie(data: &str) -> Option<CookieKey> {
     }
 }
 
-static HEXTABLE: [u8; 16] = [b'0', b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9',
-    b'A', b'B', b'C', b'D', b'E', b'F'];
+// url-safe base64 alphabet, each key character encodes 6 bits
+static KEYTABLE: &'static [u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
 
 
 impl Clone for CookieStore {
@@ -63,71 +187,307 @@ impl Clone for CookieStore {
         CookieStore {
             reader: self.reader.clone(),
             writer: self.writer.clone(),
+            journal: self.journal.clone(),
+            max_sessions: self.max_sessions,
         }
     }
 }
 
 impl CookieStore {
     pub fn new() -> CookieStore {
-        let (r, w) = evmap::new::<CookieKey, u64>();
+        let (r, w) = evmap::new::<CookieKey, Arc<Session>>();
         CookieStore {
             reader: r,
             writer: Arc::new(Mutex::new(w)),
+            journal: None,
+            max_sessions: None,
         }
     }
 
-    pub fn create_authenticated_cookie(&self) -> CookieKey {
-        let mut r = random::default();
+    /// Limit the number of sessions, when exceeded the oldest sessions get removed.
+    pub fn limit_sessions(mut self, max_sessions: usize) -> CookieStore {
+        self.max_sessions = Some(max_sessions);
+        self
+    }
+
+    /// Like `new` but persists sessions in the journal at `path`. Sessions still valid
+    /// according to the journal are restored.
+    pub fn with_session_file(path: &Path) -> io::Result<CookieStore> {
+        let (journal, records) = Journal::open(path)?;
+        let mut store = CookieStore::new();
+        {
+            let now = Self::now_unix_epoch();
+            let mut writer = store.write_handle();
+            for record in records {
+                let key = record.iter().find(|&&(ref k, _)| k == "key")
+                    .and_then(|&(_, ref v)| to_cookie(v));
+                let key = match key {
+                    Some(key) => key,
+                    None => continue,
+                };
+                match record.iter().find(|&&(ref k, _)| k == "op").map(|&(_, ref v)| v.as_str()) {
+                    Some("add") => match Session::from_record(&record) {
+                        Some(ref session) if session.expires < now => writer.empty(key),
+                        Some(session) => writer.update(key, Arc::new(session)),
+                        None => warn!("Skip invalid session record in {}", path.display()),
+                    },
+                    Some("del") => writer.empty(key),
+                    _ => warn!("Skip unknown record in {}", path.display()),
+                }
+            }
+            writer.refresh();
+            info!("Restored {} sessions from {}", writer.len(), path.display());
+        }
+        store.journal = Some(Arc::new(Mutex::new(journal)));
+        Ok(store)
+    }
+
+    fn journal_append(&self, record: &[(&str, &str)]) {
+        if let Some(ref journal) = self.journal {
+            if let Err(e) = journal.lock().unwrap().append(record) {
+                error!("Failed to write session file: {}", e);
+            }
+        }
+    }
+
+    /// Rewrites the session file with only the currently valid sessions.
+    pub fn compact_session_file(&self) {
+        let journal = match self.journal {
+            Some(ref journal) => journal,
+            None => return,
+        };
+        // holding the writer ensures no session gets added meanwhile
+        let writer = self.write_handle();
+        let now = Self::now_unix_epoch();
+        let sessions: Vec<(String, Arc<Session>)> = writer
+            .map_into(|k, v| (k.to_string(), v[0].clone()));
+        let sessions: Vec<(String, Vec<(&str, String)>)> = sessions.iter()
+            .filter(|&&(_, ref session)| session.expires >= now)
+            .map(|&(ref key, ref session)| (key.clone(), session.to_record()))
+            .collect();
+        let records = sessions.iter()
+            .map(|&(ref key, ref fields)| add_record(key, fields));
+        match journal.lock().unwrap().rewrite(records) {
+            Ok(()) => info!("Compacted session file to {} sessions", sessions.len()),
+            Err(e) => error!("Failed to compact session file: {}", e),
+        }
+    }
+
+    pub fn create_authenticated_cookie(&self, session: Session) -> CookieKey {
         let mut key = [0; 64];
+        getrandom(&mut key).expect("Failed to read random bytes from the OS");
         for it in key.iter_mut() {
-            let random: u8 = r.read();
-            let value = HEXTABLE[(random & 0x0f) as usize];
-            *it = value;
+            *it = KEYTABLE[(*it & 0x3f) as usize];
         }
 
-        let timeout = time::SystemTime::now() + time::Duration::from_secs(60 * 60 * 24); // 1 day
-        let timeout = timeout.duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_secs();
         {
             let mut writer = self.write_handle();
-            writer.insert(CookieKey(key), timeout);
-            warn!("Insert: {}", CookieKey(key).to_string());
+            if let Some(max_sessions) = self.max_sessions {
+                let sessions = writer.len();
+                if sessions >= max_sessions {
+                    self.evict_oldest(&mut writer, sessions + 1 - max_sessions);
+                }
+            }
+            let key_str = CookieKey(key).to_string();
+            self.journal_append(&add_record(&key_str, &session.to_record()));
+            writer.insert(CookieKey(key), Arc::new(session));
             writer.refresh();
         }
         CookieKey(key)
     }
 
 
-    fn write_handle(&self) -> MutexGuard<WriteHandle<CookieKey, u64>> {
+    /// Removes the session of `key`, returns whether it existed.
+    pub fn remove_session(&self, key: &CookieKey) -> bool {
+        let mut writer = self.write_handle();
+        let existed = writer.get_and(key, |_| ()).is_some();
+        if existed {
+            self.journal_append(&[("op", "del"), ("key", &key.to_string())]);
+            writer.empty(key.clone());
+            writer.refresh();
+        }
+        existed
+    }
+
+    /// Removes all sessions of `account`, returns how many were removed.
+    pub fn remove_account_sessions(&self, account: &str) -> usize {
+        let mut writer = self.write_handle();
+        let sessions: Vec<(CookieKey, Arc<Session>)> = writer
+            .map_into(|k, v| (k.clone(), v[0].clone()));
+        let keys: Vec<CookieKey> = sessions.into_iter()
+            .filter(|&(_, ref session)| session.identity.account == account)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys.iter() {
+            self.journal_append(&[("op", "del"), ("key", &key.to_string())]);
+            writer.empty(key.clone());
+        }
+        writer.refresh();
+        keys.len()
+    }
+
+    /// Replaces the session of `key`, e.g. with an extended expiry. Sessions removed
+    /// meanwhile are not brought back.
+    pub fn update_session(&self, key: &CookieKey, session: Session) -> Arc<Session> {
+        let session = Arc::new(session);
+        let mut writer = self.write_handle();
+        if writer.get_and(key, |_| ()).is_some() {
+            let key_str = key.to_string();
+            self.journal_append(&add_record(&key_str, &session.to_record()));
+            writer.update(key.clone(), session.clone());
+            writer.refresh();
+        }
+        session
+    }
+
+    fn write_handle(&self) -> MutexGuard<WriteHandle<CookieKey, Arc<Session>>> {
         self.writer.lock().unwrap()
     }
 
-    fn now_unix_epoch() -> u64 {
+    pub fn now_unix_epoch() -> u64 {
         time::SystemTime::now()
             .duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_secs()
     }
 
-    /// true -> cookie is valid until time
-    /// false -> cookie is outdated
-    pub fn is_cookie_authenticated(&self, key: &CookieKey) -> bool {
+    /// Some -> cookie is valid until session.expires
+    /// None -> cookie is unknown or outdated
+    pub fn get_authenticated_session(&self, key: &CookieKey) -> Option<Arc<Session>> {
         let reader = &self.reader;
-        let value = reader.get_and(key, |v| v[0]);
+        let value = reader.get_and(key, |v| v[0].clone());
 
-        debug!("Reading {} -> {:?}", key.to_string(), value);
-        if value.is_none() {
-            false
-        } else if value.unwrap() < Self::now_unix_epoch() {
-            // outdated, remove from map
-            let mut writer = self.write_handle();
-            writer.empty(key.clone());
-            // but no refresh - it's not urgent
-            false
-        } else {
-            true
+        // the key is a bearer token, it is not logged
+        debug!("Reading session -> {:?}", value);
+        match value {
+            Some(ref session) if session.expires < Self::now_unix_epoch() => {
+                // outdated, remove from map
+                let mut writer = self.write_handle();
+                writer.empty(key.clone());
+                self.journal_append(&[("op", "del"), ("key", &key.to_string())]);
+                // but no refresh - it's not urgent
+                None
+            }
+            value => value,
+        }
+    }
+
+    fn evict_oldest(&self, writer: &mut WriteHandle<CookieKey, Arc<Session>>, count: usize) {
+        let mut sessions: Vec<(u64, CookieKey)> = writer
+            .map_into(|k, v| (v[0].issued, k.clone()));
+        sessions.sort_by_key(|&(issued, _)| issued);
+        warn!("Session limit reached, evict the {} oldest sessions", count);
+        for (_, key) in sessions.into_iter().take(count) {
+            self.journal_append(&[("op", "del"), ("key", &key.to_string())]);
+            writer.empty(key);
         }
     }
 
-    pub fn clean_outdated_cookies(&self) {
-//        unimplemented!()
+    /// Removes all outdated sessions, returns how many were removed.
+    pub fn clean_outdated_cookies(&self) -> usize {
+        let mut writer = self.write_handle();
+        let now = Self::now_unix_epoch();
+        let sessions: Vec<(CookieKey, u64)> = writer
+            .map_into(|k, v| (k.clone(), v[0].expires));
+        let outdated: Vec<CookieKey> = sessions.into_iter()
+            .filter(|&(_, expires)| expires < now)
+            .map(|(key, _)| key)
+            .collect();
+        let removed = outdated.len();
+        for key in outdated {
+            // no journal entry, outdated sessions are dropped when loading anyway
+            writer.empty(key);
+        }
+        writer.refresh();
+        removed
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LIFETIME: SessionLifetime = SessionLifetime { idle_timeout: 3600, max_lifetime: 7200 };
+
+    fn session() -> Session {
+        Session::new(Identity::default(), Binding::default(), &LIFETIME)
+    }
+
+    #[test]
+    fn limit_sessions_evicts_oldest() {
+        let store = CookieStore::new().limit_sessions(2);
+        let key = store.create_authenticated_cookie(session());
+        {
+            // make the first session the oldest
+            let mut writer = store.write_handle();
+            writer.update(key.clone(), Arc::new(Session { issued: 1, expires: u64::max_value(),
+                                                          identity: Identity::default(),
+                                                          binding: Binding::default() }));
+            writer.refresh();
+        }
+        store.create_authenticated_cookie(session());
+        store.create_authenticated_cookie(session());
+        assert_eq!(store.reader.len(), 2);
+        assert!(store.get_authenticated_session(&key).is_none());
+    }
+
+    #[test]
+    fn clean_outdated_cookies() {
+        let store = CookieStore::new();
+        let key = store.create_authenticated_cookie(session());
+        store.create_authenticated_cookie(session());
+        {
+            let mut writer = store.write_handle();
+            writer.update(key, Arc::new(Session { issued: 1, expires: 2, identity: Identity::default(),
+                                                  binding: Binding::default() }));
+            writer.refresh();
+        }
+        assert_eq!(store.clean_outdated_cookies(), 1);
+        assert_eq!(store.reader.len(), 1);
+    }
+
+    #[test]
+    fn extend_session() {
+        let fresh = session();
+        let now = fresh.issued;
+        assert_eq!(fresh.expires, now + 3600);
+        assert_eq!(fresh.extended_at(&LIFETIME, now), None);
+
+        let idle = Session { issued: now - 1000, expires: now + 2600, ..session() };
+        assert_eq!(idle.extended_at(&LIFETIME, now).map(|s| s.expires), Some(now + 3600));
+        // not beyond the maximum lifetime
+        let old = Session { issued: now - 5000, expires: now + 1000, ..session() };
+        assert_eq!(old.extended_at(&LIFETIME, now).map(|s| s.expires), Some(now + 2200));
+        let older = Session { issued: now - 7000, expires: now + 200, ..session() };
+        assert_eq!(older.extended_at(&LIFETIME, now), None);
+    }
+
+    #[test]
+    fn update_removed_session() {
+        let store = CookieStore::new();
+        let key = store.create_authenticated_cookie(session());
+        {
+            let mut writer = store.write_handle();
+            writer.empty(key.clone());
+            writer.refresh();
+        }
+        store.update_session(&key, session());
+        assert!(store.get_authenticated_session(&key).is_none());
+    }
+
+    #[test]
+    fn remove_sessions() {
+        let store = CookieStore::new();
+        let identity = |account: &str| Identity { account: account.to_string(), ..Identity::default() };
+        let alice = store.create_authenticated_cookie(Session::new(identity("alice"), Binding::default(), &LIFETIME));
+        store.create_authenticated_cookie(Session::new(identity("alice"), Binding::default(), &LIFETIME));
+        let bob = store.create_authenticated_cookie(Session::new(identity("bob"), Binding::default(), &LIFETIME));
+
+        assert!(store.remove_session(&alice));
+        assert!(!store.remove_session(&alice));
+        assert!(store.get_authenticated_session(&alice).is_none());
+        store.create_authenticated_cookie(Session::new(identity("alice"), Binding::default(), &LIFETIME));
+        assert_eq!(store.remove_account_sessions("alice"), 2);
+        assert_eq!(store.reader.len(), 1);
+        assert!(store.get_authenticated_session(&bob).is_some());
+    }
+}
@@ -1,77 +1,312 @@
 use std::fmt;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{Shutdown, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::boxed::Box;
+use std::time::Duration;
 
 use bytes::Bytes;
 use bytes::BytesMut;
 use tokio;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::reactor::Handle;
 use tokio::prelude::*;
 use tokio::codec::{Encoder, Decoder};
-use http::header::HeaderValue;
-use http::{Request, Response};
+use futures::future::{Either, Loop};
+use http::header::{HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING};
+use http::{request, Request, Response, StatusCode, Version};
 use thread_local::ThreadLocal;
 
+use proxy::{ClientIp, TrustedProxies};
+use unix_socket::{self, SocketOwner};
+
 pub trait HttpHandler<T> {
     fn respond(&self, state: &T, req: Request<Bytes>) -> Response<String>;
 }
 
+/// Where the server listens: a TCP address like `127.0.0.1:8080` or a Unix socket like
+/// `unix:/run/totp.sock`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("unix:") {
+            let path = &s["unix:".len()..];
+            if path.is_empty() {
+                return Err("missing path of unix socket".to_string());
+            }
+            return Ok(Listen::Unix(PathBuf::from(path)));
+        }
+        s.parse().map(Listen::Tcp)
+            .map_err(|_| format!("invalid address '{}', expected host:port or unix:/path", s))
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Listen::Tcp(ref addr) => write!(f, "{}", addr),
+            Listen::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Other end of a connection, made available to the handler as request extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// a local process connected to the Unix socket
+    Unix,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Peer::Tcp(ref addr) => write!(f, "{}", addr),
+            Peer::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+/// Stream of an accepted connection
+trait Connection: AsyncRead + AsyncWrite + Send + 'static {
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+impl Connection for UnixStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+/// Settings of the HTTP server
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// proxies whose headers name the client address
+    pub trusted_proxies: TrustedProxies,
+    /// how long a persistent connection may wait for the next request
+    pub keep_alive_timeout: Duration,
+    /// requests served on one connection before it is closed
+    pub max_requests_per_connection: usize,
+    /// largest request body accepted, in bytes
+    pub max_body_size: usize,
+    /// most headers accepted in a request
+    pub max_headers: usize,
+    /// file mode of a Unix socket
+    pub socket_mode: u32,
+    /// owner of a Unix socket, the user running the server if None
+    pub socket_owner: Option<SocketOwner>,
+}
+
+/// Whether the client wants the connection kept open after the response: HTTP/1.1
+/// unless it sends `Connection: close`, HTTP/1.0 only with `Connection: keep-alive`.
+fn wants_keep_alive(req: &Request<Bytes>) -> bool {
+    let has_token = |token: &str| req.headers().get_all(CONNECTION).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token));
+    if has_token("close") {
+        false
+    } else if req.version() == Version::HTTP_10 {
+        has_token("keep-alive")
+    } else {
+        true
+    }
+}
+
+/// Whether the connection stays open after answering `req`, with `served` requests
+/// answered before on the connection, and the Connection header of the response.
+fn keep_alive(req: &Request<Bytes>, served: usize, max_requests: usize) -> (bool, Option<HeaderValue>) {
+    if !wants_keep_alive(req) || served + 1 >= max_requests {
+        (false, Some(HeaderValue::from_static("close")))
+    } else if req.version() == Version::HTTP_10 {
+        (true, Some(HeaderValue::from_static("keep-alive")))
+    } else {
+        (true, None)
+    }
+}
+
+/// Answer to a request that could not be read, the connection is closed afterwards.
+fn error_response(status: StatusCode, reason: String) -> Response<String> {
+    let mut response = Response::new(format!("{}\n", reason));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    response.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+    response
+}
+
+/// How long data is still read after an error response
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Closes the connection after an error response. Data the client still sends is read and
+/// dropped for a moment, closing with unread data would reset the connection and the
+/// client could lose the response.
+fn lingering_close<S: Connection>(socket: S) -> impl Future<Item=(), Error=io::Error> {
+    // AsyncWrite::shutdown of TcpStream does not shut down the socket
+    future::result(socket.shutdown_write().map(|_| socket))
+        .and_then(|socket| {
+            future::loop_fn((socket, vec![0; 4096]), |(socket, buf)| {
+                tokio::io::read(socket, buf).map(|(socket, buf, amt)| {
+                    if amt == 0 {
+                        Loop::Break(())
+                    } else {
+                        Loop::Continue((socket, buf))
+                    }
+                })
+            }).timeout(LINGER_TIMEOUT)
+                .then(|_| Ok(()))
+        })
+}
+
 pub fn serve<
     T: 'static + Send + Clone + HttpHandler<X>,
     X: Send + Clone + 'static,
->(addr: SocketAddr, state: X, handler: T)
+>(listen: &Listen, config: ServerConfig, state: X, handler: T)
+  -> impl Future<Item=(), Error=()> + Send
+{
+    match *listen {
+        Listen::Tcp(ref addr) => {
+            let listener = TcpListener::bind(addr).expect("failed to bind");
+            info!("Listening on: {}", listen);
+            let incoming = listener.incoming().map(|socket| {
+                let peer = socket.peer_addr().ok().map(Peer::Tcp);
+                (socket, peer)
+            });
+            Either::A(serve_connections(incoming, config, state, handler))
+        }
+        Listen::Unix(ref path) => {
+            let listener = unix_socket::bind(path, config.socket_mode, config.socket_owner)
+                .and_then(|listener| UnixListener::from_std(listener, &Handle::default()))
+                .unwrap_or_else(|e| panic!("failed to bind {}: {}", listen, e));
+            info!("Listening on: {}", listen);
+            let incoming = listener.incoming().map(|socket| (socket, Some(Peer::Unix)));
+            Either::B(serve_connections(incoming, config, state, handler))
+        }
+    }
+}
+
+fn serve_connections<
+    T: 'static + Send + Clone + HttpHandler<X>,
+    X: Send + Clone + 'static,
+    S: Connection,
+    I: Stream<Item=(S, Option<Peer>), Error=io::Error> + Send,
+>(incoming: I, config: ServerConfig, state: X, handler: T)
   -> impl Future<Item=(), Error=()> + Send
 {
-    let listener = TcpListener::bind(&addr).expect("failed to bind");
-    info!("Listening on: {}", addr);
     let tl_handler: Arc<ThreadLocal<T>> = Arc::new(ThreadLocal::new());
     let tl_state: Arc<ThreadLocal<X>> = Arc::new(ThreadLocal::new());
+    let config = Arc::new(config);
 
-    listener.incoming()
+    incoming
         .map_err(|e| error!("failed to accept socket; error = {:?}", e))
-        .for_each(move |socket| {
-            let peer_addr = match socket.peer_addr() {
-                Ok(addr) => format!("{}", addr),
-                Err(_) => "<error>".to_string(),
-            };
-
-            let (tx, rx) =
-                HttpFrame.framed(socket).split();
+        .for_each(move |(socket, peer)| {
 
             let tl_handler = tl_handler.clone();
             let handler = handler.clone();
 
             let tl_state = tl_state.clone();
             let state = state.clone();
-
-            let rx_task = rx.and_then(move |req| {
-                let state = tl_state.get_or(|| {
-                    Box::new(state.clone())
-                });
-                let handler = tl_handler.get_or(|| {
-                    Box::new(handler.clone())
-                });
-                info!("{:?} {} {} {:?}", peer_addr, req.method(), req.uri(), req.version());
-                let response = handler.respond(&state, req);
-                future::ok(response)
-            })
-                // We limit to one http-frame (request-response cycle)
-                // and do not heandle Connection: keep-alive.
-                // Nginx anyway does Connection: close for auth_request.
-                .take(1);
-
-            let tx_task = tx.send_all(rx_task)
-                .then(|res| {
-                    if let Err(r) = res {
-                        error!("ERROR: {:?}", r)
-                    }
-                    Ok(())
-                });
+            let config = config.clone();
+
+            // Serves requests one after the other until the client or the limits close
+            // the connection.
+            let frame = HttpFrame::new(config.max_body_size, config.max_headers);
+            let connection = future::loop_fn((frame.framed(socket), 0), move |(framed, served)| {
+                let tl_handler = tl_handler.clone();
+                let handler = handler.clone();
+                let tl_state = tl_state.clone();
+                let state = state.clone();
+                let config = config.clone();
+
+                framed.into_future()
+                    .map_err(|(e, _)| e)
+                    .timeout(config.keep_alive_timeout)
+                    .map_err(move |e| match e.into_inner() {
+                        Some(e) => e,
+                        None => io::Error::new(io::ErrorKind::TimedOut, "idle connection timed out"),
+                    })
+                    .and_then(move |(frame, framed)| {
+                        let mut req = match frame {
+                            Some(Frame::Request(req)) => req,
+                            Some(Frame::Error(status, reason)) => {
+                                warn!("{} {}: {}", peer.as_ref().map_or("<error>".to_string(), |peer| peer.to_string()),
+                                      status, reason);
+                                let response = error_response(status, reason);
+                                let close = framed.send(response)
+                                    .and_then(|framed| lingering_close(framed.into_inner()))
+                                    .map(|_| Loop::Break(()));
+                                return Either::A(Either::B(close));
+                            }
+                            // closed by the client
+                            None => return Either::A(Either::A(future::ok(Loop::Break(())))),
+                        };
+                        let state = tl_state.get_or(|| {
+                            Box::new(state.clone())
+                        });
+                        let handler = tl_handler.get_or(|| {
+                            Box::new(handler.clone())
+                        });
+                        let client_ip = match peer {
+                            Some(Peer::Tcp(addr)) => Some(config.trusted_proxies.client_ip(addr.ip(), req.headers())),
+                            // only processes allowed to open the socket file connect, like a proxy
+                            Some(Peer::Unix) => config.trusted_proxies.forwarded_client_ip(req.headers()),
+                            None => None,
+                        };
+                        let client = match (client_ip, &peer) {
+                            (Some(client), &Some(Peer::Tcp(addr))) if client == addr.ip() => addr.to_string(),
+                            (Some(client), &Some(ref peer)) => format!("{} via {}", client, peer),
+                            (None, &Some(ref peer)) => peer.to_string(),
+                            (_, &None) => "<error>".to_string(),
+                        };
+                        // made available to the handler as the peer and the client address
+                        if let Some(peer) = peer {
+                            req.extensions_mut().insert(peer);
+                        }
+                        if let Some(client_ip) = client_ip {
+                            req.extensions_mut().insert(ClientIp(client_ip));
+                        }
+                        info!("{} {} {} {:?}", client, req.method(), req.uri(), req.version());
+
+                        let (keep_alive, connection_header) =
+                            keep_alive(&req, served, config.max_requests_per_connection);
+                        let mut response = handler.respond(&state, req);
+                        if let Some(value) = connection_header {
+                            response.headers_mut().insert(CONNECTION, value);
+                        }
+
+                        Either::B(framed.send(response).map(move |framed| {
+                            if keep_alive {
+                                Loop::Continue((framed, served + 1))
+                            } else {
+                                Loop::Break(())
+                            }
+                        }))
+                    })
+            }).then(|res| {
+                match res {
+                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => debug!("{}", e),
+                    Err(e) => error!("ERROR: {:?}", e),
+                    Ok(()) => (),
+                }
+                Ok(())
+            });
 
             // Spawn the task that handles the connection.
-            tokio::spawn(tx_task);
+            tokio::spawn(connection);
             Ok(())
         })
 }
@@ -80,7 +315,25 @@ pub fn serve<
 /// The following code is mostly copied from:
 /// https://github.com/tokio-rs/tokio/blob/master/examples/tinyhttp.rs
 ///-------------------------------------------------------------------------------------------------
-struct HttpFrame;
+struct HttpFrame {
+    /// requests with larger bodies are answered with 413
+    max_body_size: usize,
+    /// requests with more headers are answered with 431
+    max_headers: usize,
+    /// headers parsed at once, grown up to `max_headers` when needed
+    header_capacity: usize,
+    /// head of a request whose body is not yet complete
+    pending: Option<(request::Parts, BodyLength)>,
+}
+
+const INITIAL_HEADER_CAPACITY: usize = 16;
+
+/// What the decoder produces: a request, or the error to answer before closing the
+/// connection because the request can not be read.
+enum Frame {
+    Request(Request<Bytes>),
+    Error(StatusCode, String),
+}
 
 /// Implementation of encoding an HTTP response into a `BytesMut`, basically
 /// just writing out an HTTP/1.1 response.
@@ -136,20 +389,83 @@ impl Encoder for HttpFrame {
 /// that information to construct an instance of a `http::Request` object,
 /// trying to avoid allocations where possible.
 impl Decoder for HttpFrame {
-    type Item = Request<Bytes>;
+    type Item = Frame;
     type Error = io::Error;
 
-    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Request<Bytes>>> {
-        // TODO: we should grow this headers array if parsing fails and asks
-        //       for more headers
-        let mut headers = [None; 16];
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame>> {
+        if self.pending.is_none() {
+            let head = match self.decode_head(src) {
+                Ok(Some(head)) => head,
+                Ok(None) => return Ok(None),
+                Err((status, reason)) => return Ok(Some(Frame::Error(status, reason))),
+            };
+            let length = match body_length(&head, self.max_body_size) {
+                Ok(length) => length,
+                Err((status, reason)) => return Ok(Some(Frame::Error(status, reason))),
+            };
+            self.pending = Some((head, length));
+        }
+
+        let body = match self.pending.as_ref().map(|&(_, ref length)| *length) {
+            Some(BodyLength::Fixed(length)) => {
+                if src.len() < length {
+                    src.reserve(length - src.len());
+                    return Ok(None);
+                }
+                src.split_to(length).freeze()
+            }
+            Some(BodyLength::Chunked) => match decode_chunked(src, self.max_body_size) {
+                Ok(Some(body)) => body,
+                Ok(None) => return Ok(None),
+                Err((status, reason)) => return Ok(Some(Frame::Error(status, reason))),
+            },
+            None => unreachable!(),
+        };
+        let (head, _) = self.pending.take().unwrap();
+        Ok(Some(Frame::Request(Request::from_parts(head, body))))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame>> {
+        let frame = self.decode(src)?;
+        if frame.is_none() && !src.is_empty() {
+            // nobody left to answer to
+            debug!("Connection closed with an incomplete request");
+            src.clear();
+        }
+        Ok(frame)
+    }
+}
+
+impl HttpFrame {
+    fn new(max_body_size: usize, max_headers: usize) -> HttpFrame {
+        HttpFrame {
+            max_body_size,
+            max_headers,
+            header_capacity: INITIAL_HEADER_CAPACITY.min(max_headers),
+            pending: None,
+        }
+    }
+
+    /// Parses the request line and headers, the body is left in `src`.
+    fn decode_head(&mut self, src: &mut BytesMut) -> Result<Option<request::Parts>, FrameError> {
+        let mut headers = Vec::new();
         let (method, path, version, amt) = {
-            let mut parsed_headers = [httparse::EMPTY_HEADER; 16];
+            let mut parsed_headers = vec![httparse::EMPTY_HEADER; self.header_capacity];
             let mut r = httparse::Request::new(&mut parsed_headers);
-            let status = r.parse(src).map_err(|e| {
-                let msg = format!("failed to parse http request: {:?}", e);
-                io::Error::new(io::ErrorKind::Other, msg)
-            })?;
+            let status = match r.parse(src) {
+                Ok(status) => status,
+                Err(httparse::Error::TooManyHeaders) if self.header_capacity < self.max_headers => {
+                    // kept for further requests on this connection
+                    self.header_capacity = (self.header_capacity * 2).min(self.max_headers);
+                    return self.decode_head(src);
+                }
+                Err(httparse::Error::TooManyHeaders) =>
+                    return Err((StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                                format!("more than {} headers", self.max_headers))),
+                Err(httparse::Error::Version) =>
+                    return Err((StatusCode::HTTP_VERSION_NOT_SUPPORTED, "only HTTP/1.x is supported".to_string())),
+                Err(e) => return Err((StatusCode::BAD_REQUEST, format!("failed to parse http request: {}", e))),
+            };
 
             let amt = match status {
                 httparse::Status::Complete(amt) => amt,
@@ -162,10 +478,8 @@ impl Decoder for HttpFrame {
                 (start, start + a.len())
             };
 
-            for (i, header) in r.headers.iter().enumerate() {
-                let k = toslice(header.name.as_bytes());
-                let v = toslice(header.value);
-                headers[i] = Some((k, v));
+            for header in r.headers.iter() {
+                headers.push((toslice(header.name.as_bytes()), toslice(header.value)));
             }
 
             (toslice(r.method.unwrap().as_bytes()),
@@ -173,33 +487,138 @@ impl Decoder for HttpFrame {
              r.version.unwrap(),
              amt)
         };
-        if version != 1 && version != 0 { // TODO
-            error!("Version: {}", version);
-            return Err(io::Error::new(io::ErrorKind::Other, "only HTTP/1.1 accepted"));
+        if version != 1 && version != 0 {
+            return Err((StatusCode::HTTP_VERSION_NOT_SUPPORTED, "only HTTP/1.x is supported".to_string()));
         }
         let data = src.split_to(amt).freeze();
         let mut req_builder = Request::builder();
         req_builder.method(&data[method.0..method.1]);
         req_builder.uri(data.slice(path.0, path.1));
-        req_builder.version(http::Version::HTTP_11);
-        for header in headers.iter() {
-            let (k, v) = match *header {
-                Some((ref k, ref v)) => (k, v),
-                None => break,
-            };
+        req_builder.version(if version == 0 { Version::HTTP_10 } else { Version::HTTP_11 });
+        for &(ref k, ref v) in headers.iter() {
             let value = unsafe {
                 HeaderValue::from_shared_unchecked(data.slice(v.0, v.1))
             };
             req_builder.header(&data[k.0..k.1], value);
         }
 
-
-        let request_body = src.split_off(0).freeze();
-        let req = req_builder.body(request_body).map_err(|e| {
-            io::Error::new(io::ErrorKind::Other, e)
+        let req = req_builder.body(()).map_err(|e| {
+            (StatusCode::BAD_REQUEST, format!("invalid http request: {}", e))
         })?;
-        Ok(Some(req))
+        Ok(Some(req.into_parts().0))
+    }
+}
+
+/// How the end of a request body is found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BodyLength {
+    Fixed(usize),
+    Chunked,
+}
+
+type FrameError = (StatusCode, String);
+
+/// Body framing according to Transfer-Encoding and Content-Length, a request without
+/// either has no body.
+fn body_length(head: &request::Parts, max_body_size: usize) -> Result<BodyLength, FrameError> {
+    let header_values = |name: HeaderName| -> Result<Vec<&str>, FrameError> {
+        head.headers.get_all(&name).iter()
+            .map(|value| value.to_str().map_err(|_| (StatusCode::BAD_REQUEST, format!("invalid {} header", name))))
+            .collect()
+    };
+    let transfer_encodings = header_values(TRANSFER_ENCODING)?;
+    let content_lengths = header_values(CONTENT_LENGTH)?;
+
+    if !transfer_encodings.is_empty() {
+        // both would allow to smuggle requests past a proxy that picks the other one
+        if !content_lengths.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, "both Transfer-Encoding and Content-Length given".to_string()));
+        }
+        let last = transfer_encodings.iter()
+            .flat_map(|value| value.split(','))
+            .map(|coding| coding.trim())
+            .filter(|coding| !coding.is_empty())
+            .last();
+        return match last {
+            Some(coding) if coding.eq_ignore_ascii_case("chunked") => Ok(BodyLength::Chunked),
+            _ => Err((StatusCode::BAD_REQUEST, "unsupported Transfer-Encoding".to_string())),
+        };
+    }
+
+    let mut length = None;
+    for value in content_lengths.iter().flat_map(|value| value.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err((StatusCode::BAD_REQUEST, "invalid Content-Length".to_string()));
+        }
+        // too many digits for usize is too large anyway
+        let value = value.parse::<usize>().unwrap_or(usize::max_value());
+        if length.map_or(false, |length| length != value) {
+            return Err((StatusCode::BAD_REQUEST, "conflicting Content-Length".to_string()));
+        }
+        length = Some(value);
+    }
+    match length {
+        Some(length) if length > max_body_size =>
+            Err((StatusCode::PAYLOAD_TOO_LARGE, format!("request body larger than {} bytes", max_body_size))),
+        Some(length) => Ok(BodyLength::Fixed(length)),
+        None => Ok(BodyLength::Fixed(0)),
+    }
+}
+
+/// Longest chunk size or trailer line accepted
+const MAX_CHUNK_LINE: usize = 4096;
+
+/// Decodes a chunked body once it is complete in `src`, the body and trailers are then
+/// removed from `src`. Trailers are dropped.
+fn decode_chunked(src: &mut BytesMut, max_body_size: usize) -> Result<Option<Bytes>, FrameError> {
+    let invalid = || (StatusCode::BAD_REQUEST, "invalid chunked encoding".to_string());
+    let mut body = BytesMut::new();
+    let mut pos = 0;
+    loop {
+        match httparse::parse_chunk_size(&src[pos..]) {
+            Ok(httparse::Status::Complete((amt, 0))) => {
+                pos += amt;
+                break;
+            }
+            Ok(httparse::Status::Complete((amt, size))) => {
+                // the chunk size has up to 16 hex digits
+                match (body.len() as u64).checked_add(size) {
+                    Some(len) if len <= max_body_size as u64 => (),
+                    _ => return Err((StatusCode::PAYLOAD_TOO_LARGE,
+                                     format!("request body larger than {} bytes", max_body_size))),
+                }
+                let size = size as usize;
+                let start = pos + amt;
+                let end = start.checked_add(size).and_then(|end| end.checked_add(2)).ok_or_else(invalid)?;
+                if src.len() < end {
+                    return Ok(None);
+                }
+                if &src[start + size..start + size + 2] != b"\r\n" {
+                    return Err(invalid());
+                }
+                body.extend_from_slice(&src[start..start + size]);
+                pos = start + size + 2;
+            }
+            Ok(httparse::Status::Partial) if src.len() - pos > MAX_CHUNK_LINE => return Err(invalid()),
+            Ok(httparse::Status::Partial) => return Ok(None),
+            Err(_) => return Err(invalid()),
+        }
+    }
+    // trailer fields up to an empty line
+    loop {
+        match src[pos..].windows(2).position(|w| w == b"\r\n") {
+            Some(0) => {
+                pos += 2;
+                break;
+            }
+            Some(line) if line <= MAX_CHUNK_LINE => pos += line + 2,
+            None if src.len() - pos <= MAX_CHUNK_LINE => return Ok(None),
+            _ => return Err(invalid()),
+        }
     }
+    src.split_to(pos);
+    Ok(Some(body.freeze()))
 }
 
 mod date {
@@ -284,3 +703,167 @@ mod date {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(frame: &mut HttpFrame, buf: &mut BytesMut, data: &[u8]) -> Option<Frame> {
+        buf.extend_from_slice(data);
+        frame.decode(buf).unwrap()
+    }
+
+    fn body(frame: Option<Frame>) -> Bytes {
+        match frame {
+            Some(Frame::Request(req)) => req.into_body(),
+            Some(Frame::Error(status, reason)) => panic!("{} {}", status, reason),
+            None => panic!("incomplete"),
+        }
+    }
+
+    fn status(frame: Option<Frame>) -> StatusCode {
+        match frame {
+            Some(Frame::Error(status, _)) => status,
+            _ => panic!("no error"),
+        }
+    }
+
+    fn request(version: Version, connection: Option<&'static str>) -> Request<Bytes> {
+        let mut req = Request::builder();
+        req.version(version);
+        if let Some(connection) = connection {
+            req.header(CONNECTION, connection);
+        }
+        req.body(Bytes::new()).unwrap()
+    }
+
+    #[test]
+    fn connection_keep_alive() {
+        assert!(wants_keep_alive(&request(Version::HTTP_11, None)));
+        assert!(wants_keep_alive(&request(Version::HTTP_11, Some("Keep-Alive"))));
+        assert!(!wants_keep_alive(&request(Version::HTTP_11, Some("close"))));
+        assert!(!wants_keep_alive(&request(Version::HTTP_11, Some("TE, Close"))));
+        assert!(!wants_keep_alive(&request(Version::HTTP_10, None)));
+        assert!(wants_keep_alive(&request(Version::HTTP_10, Some("keep-alive"))));
+        assert!(!wants_keep_alive(&request(Version::HTTP_10, Some("keep-alive, close"))));
+    }
+
+    #[test]
+    fn requests_per_connection() {
+        let close = Some(HeaderValue::from_static("close"));
+        let req = request(Version::HTTP_11, None);
+        assert_eq!(keep_alive(&req, 0, 3), (true, None));
+        assert_eq!(keep_alive(&req, 1, 3), (true, None));
+        assert_eq!(keep_alive(&req, 2, 3), (false, close.clone()));
+        assert_eq!(keep_alive(&req, 0, 1), (false, close.clone()));
+
+        let req = request(Version::HTTP_10, Some("keep-alive"));
+        assert_eq!(keep_alive(&req, 0, 2), (true, Some(HeaderValue::from_static("keep-alive"))));
+        assert_eq!(keep_alive(&req, 1, 2), (false, close.clone()));
+        assert_eq!(keep_alive(&request(Version::HTTP_11, Some("close")), 0, 2), (false, close));
+    }
+
+    #[test]
+    fn body_in_separate_segment() {
+        let mut frame = HttpFrame::new(100, 100);
+        let mut buf = BytesMut::new();
+        assert!(decode(&mut frame, &mut buf, b"POST /login HTTP/1.1\r\nContent-Length: 7\r\n\r\n").is_none());
+        assert!(decode(&mut frame, &mut buf, b"token").is_none());
+        assert_eq!(body(decode(&mut frame, &mut buf, b"=1GET / HTTP/1.1\r\n\r\n")), "token=1");
+        assert_eq!(body(frame.decode(&mut buf).unwrap()), "");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn chunked_body() {
+        let mut frame = HttpFrame::new(100, 100);
+        let mut buf = BytesMut::new();
+        let head = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert!(decode(&mut frame, &mut buf, head).is_none());
+        assert!(decode(&mut frame, &mut buf, b"5;ext=1\r\ntoken\r\n").is_none());
+        assert!(decode(&mut frame, &mut buf, b"2\r\n=1\r\n0\r\nX-Trailer: a\r\n").is_none());
+        assert_eq!(body(decode(&mut frame, &mut buf, b"\r\n")), "token=1");
+        assert!(buf.is_empty());
+
+        assert!(decode(&mut frame, &mut buf, head).is_none());
+        assert_eq!(status(decode(&mut frame, &mut buf, b"5\r\ntokenXX")), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn body_too_large() {
+        let mut frame = HttpFrame::new(4, 100);
+        let mut buf = BytesMut::new();
+        assert_eq!(status(decode(&mut frame, &mut buf, b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n")),
+                   StatusCode::PAYLOAD_TOO_LARGE);
+
+        let mut frame = HttpFrame::new(4, 100);
+        let mut buf = BytesMut::new();
+        assert_eq!(status(decode(&mut frame, &mut buf,
+                                 b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\n")),
+                   StatusCode::PAYLOAD_TOO_LARGE);
+
+        // chunk sizes that overflow
+        let head = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let mut frame = HttpFrame::new(4, 100);
+        let mut buf = BytesMut::from(&head[..]);
+        assert_eq!(status(decode(&mut frame, &mut buf, b"1\r\na\r\nFFFFFFFFFFFFFFFF\r\n")),
+                   StatusCode::PAYLOAD_TOO_LARGE);
+        let mut frame = HttpFrame::new(usize::max_value(), 100);
+        let mut buf = BytesMut::from(&head[..]);
+        assert_eq!(status(decode(&mut frame, &mut buf, b"FFFFFFFFFFFFFFFF\r\n")), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn ambiguous_framing() {
+        let mut buf = BytesMut::new();
+        let request = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n";
+        assert_eq!(status(decode(&mut HttpFrame::new(10, 100), &mut buf, request)), StatusCode::BAD_REQUEST);
+        let mut buf = BytesMut::new();
+        let request = b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\n";
+        assert_eq!(status(decode(&mut HttpFrame::new(10, 100), &mut buf, request)), StatusCode::BAD_REQUEST);
+        let mut buf = BytesMut::new();
+        let request = b"POST / HTTP/1.1\r\nContent-Length: +3\r\n\r\n";
+        assert_eq!(status(decode(&mut HttpFrame::new(10, 100), &mut buf, request)), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn many_headers() {
+        let mut request = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..40 {
+            request.extend_from_slice(format!("X-Totp-Secret: {}\r\n", i).as_bytes());
+        }
+        request.extend_from_slice(b"\r\n");
+
+        let mut frame = HttpFrame::new(10, 50);
+        let mut buf = BytesMut::from(&request[..]);
+        match frame.decode(&mut buf).unwrap() {
+            Some(Frame::Request(req)) => assert_eq!(req.headers().get_all("X-Totp-Secret").iter().count(), 40),
+            _ => panic!("no request"),
+        }
+
+        let mut buf = BytesMut::from(&request[..]);
+        assert_eq!(status(HttpFrame::new(10, 30).decode(&mut buf).unwrap()),
+                   StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[test]
+    fn malformed_requests() {
+        let mut frame = HttpFrame::new(10, 10);
+        assert_eq!(status(decode(&mut frame, &mut BytesMut::new(), b"\x16\x03\x01 garbage\r\n\r\n")),
+                   StatusCode::BAD_REQUEST);
+        let mut frame = HttpFrame::new(10, 10);
+        assert_eq!(status(decode(&mut frame, &mut BytesMut::new(), b"GET / HTTP/2.0\r\n\r\n")),
+                   StatusCode::HTTP_VERSION_NOT_SUPPORTED);
+        let mut frame = HttpFrame::new(10, 10);
+        assert_eq!(status(decode(&mut frame, &mut BytesMut::new(), b"GET / HTTP/1.1\r\nBad Header: x\r\n\r\n")),
+                   StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn incomplete_request_at_eof() {
+        let mut frame = HttpFrame::new(10, 10);
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\nHost: a"[..]);
+        assert!(frame.decode_eof(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs;
+use std::io;
+use std::io::{BufRead, Read, Seek, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use url::form_urlencoded;
+
+/// A record is a list of key/value pairs
+pub type Record = Vec<(String, String)>;
+
+/// Append-only file of records, one per line.
+///
+/// Every line has the form `<checksum> <record>`, where the record is form-urlencoded
+/// and the checksum is the FNV-1a hash of the record text. Lines that fail the checksum,
+/// e.g. a partially written last line after a crash, are skipped when loading.
+pub struct Journal {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl Journal {
+    /// Opens (or creates) the journal at `path` and returns it together with all valid
+    /// records it contains.
+    pub fn open(path: &Path) -> io::Result<(Journal, Vec<Record>)> {
+        let records = match fs::File::open(path) {
+            Ok(file) => Self::read_records(path, file)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut file = Self::open_options().read(true).append(true).open(path)?;
+        Self::terminate_last_line(&mut file)?;
+        Ok((Journal { path: path.to_path_buf(), file }, records))
+    }
+
+    /// A partially written last line is ended, such that the next record starts on a line
+    /// of its own instead of being glued to it.
+    fn terminate_last_line(file: &mut fs::File) -> io::Result<()> {
+        if file.metadata()?.len() == 0 {
+            return Ok(());
+        }
+        let mut last = [0];
+        file.seek(io::SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn open_options() -> fs::OpenOptions {
+        let mut options = fs::OpenOptions::new();
+        // records may contain credentials
+        options.create(true).mode(0o600);
+        options
+    }
+
+    fn read_records<R: io::Read>(path: &Path, file: R) -> io::Result<Vec<Record>> {
+        let mut records = Vec::new();
+        let mut reader = io::BufReader::new(file);
+        let mut line = Vec::new();
+        let mut line_number = 0;
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            line_number += 1;
+            match Self::parse_line(&line) {
+                Some(record) => records.push(record),
+                None => warn!("Skip corrupt record in {} line {}", path.display(), line_number),
+            }
+            line.clear();
+        }
+        Ok(records)
+    }
+
+    fn parse_line(line: &[u8]) -> Option<Record> {
+        if line.last() != Some(&b'\n') {
+            return None;
+        }
+        let line = &line[..line.len() - 1];
+        let separator = line.iter().position(|b| *b == b' ')?;
+        let (checksum, text) = (&line[..separator], &line[separator + 1..]);
+        let checksum = u64::from_str_radix(::std::str::from_utf8(checksum).ok()?, 16).ok()?;
+        if checksum != fnv1a(text) {
+            return None;
+        }
+        Some(form_urlencoded::parse(text).into_owned().collect())
+    }
+
+    fn format_line(record: &[(&str, &str)]) -> String {
+        let text = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(record)
+            .finish();
+        format!("{:016x} {}\n", fnv1a(text.as_bytes()), text)
+    }
+
+    pub fn append(&mut self, record: &[(&str, &str)]) -> io::Result<()> {
+        self.file.write_all(Self::format_line(record).as_bytes())
+    }
+
+    /// Replaces the content of the journal with `records`.
+    pub fn rewrite<'a, I>(&mut self, records: I) -> io::Result<()>
+        where I: IntoIterator<Item=Vec<(&'a str, &'a str)>> {
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut tmp_file = io::BufWriter::new(
+                Self::open_options().write(true).truncate(true).open(&tmp_path)?);
+            for record in records {
+                tmp_file.write_all(Self::format_line(&record).as_bytes())?;
+            }
+            tmp_file.into_inner()?.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = Self::open_options().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn skips_corrupt_lines() {
+        let good = Journal::format_line(&[("op", "add"), ("key", "a b&c")]);
+        let mut tampered = good.clone().into_bytes();
+        tampered[20] = b'x';
+        let data = [good.as_bytes(), b"garbage\n", &tampered, good.as_bytes(), &good.as_bytes()[..10]]
+            .concat();
+
+        let records = Journal::read_records(Path::new("test"), data.as_slice()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], vec![("op".to_string(), "add".to_string()),
+                                    ("key".to_string(), "a b&c".to_string())]);
+    }
+    #[test]
+    fn append_after_partial_line() {
+        let path = ::std::env::temp_dir().join(format!("nginx-auth-totp-journal-{}", ::std::process::id()));
+        let good = Journal::format_line(&[("op", "add")]);
+        fs::write(&path, [good.as_bytes(), &good.as_bytes()[..10]].concat()).unwrap();
+        {
+            let (mut journal, records) = Journal::open(&path).unwrap();
+            assert_eq!(records.len(), 1);
+            journal.append(&[("op", "remove")]).unwrap();
+        }
+        let (_, records) = Journal::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1], vec![("op".to_string(), "remove".to_string())]);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use journal::Journal;
+use totp;
+
+/// Lock an account after `threshold` consecutive failed logins within `window` seconds,
+/// for `duration` seconds. A threshold of 0 disables locking.
+#[derive(Clone, Copy, Debug)]
+pub struct LockoutPolicy {
+    pub threshold: u32,
+    pub window: u64,
+    pub duration: u64,
+}
+
+struct Failures {
+    count: u32,
+    first: u64,
+}
+
+struct LockoutState {
+    failures: HashMap<String, Failures>,
+    /// locked accounts and until when they are locked
+    locked: HashMap<String, u64>,
+}
+
+/// Locks accounts (user names or secret fingerprints) after repeated failed logins.
+/// Locks are optionally persisted in a journal file.
+#[derive(Clone)]
+pub struct Lockout {
+    policy: LockoutPolicy,
+    state: Arc<Mutex<LockoutState>>,
+    journal: Option<Arc<Mutex<Journal>>>,
+}
+
+impl Lockout {
+    pub fn new(policy: LockoutPolicy) -> Lockout {
+        Lockout {
+            policy,
+            state: Arc::new(Mutex::new(LockoutState {
+                failures: HashMap::new(),
+                locked: HashMap::new(),
+            })),
+            journal: None,
+        }
+    }
+
+    /// Like `new` but persists locks in the journal at `path`, restoring the locks from it.
+    pub fn with_lock_file(policy: LockoutPolicy, path: &Path) -> io::Result<Lockout> {
+        let (journal, records) = Journal::open(path)?;
+        let mut lockout = Lockout::new(policy);
+        {
+            let now = totp::now();
+            let mut state = lockout.state.lock().unwrap();
+            for record in records {
+                let field = |name| record.iter().find(|&&(ref k, _)| k == name).map(|&(_, ref v)| v);
+                match (field("op").map(|op| op.as_str()), field("account")) {
+                    (Some("lock"), Some(account)) => match field("until").and_then(|u| u.parse().ok()) {
+                        Some(until) if until > now => {
+                            state.locked.insert(account.clone(), until);
+                        }
+                        Some(_) => {
+                            state.locked.remove(account);
+                        }
+                        None => warn!("Skip invalid lock record in {}", path.display()),
+                    },
+                    (Some("unlock"), Some(account)) => {
+                        state.locked.remove(account);
+                    }
+                    _ => warn!("Skip unknown record in {}", path.display()),
+                }
+            }
+            info!("Restored {} locked accounts from {}", state.locked.len(), path.display());
+        }
+        lockout.journal = Some(Arc::new(Mutex::new(journal)));
+        Ok(lockout)
+    }
+
+    fn journal_append(&self, record: &[(&str, &str)]) {
+        if let Some(ref journal) = self.journal {
+            if let Err(e) = journal.lock().unwrap().append(record) {
+                error!("Failed to write lock file: {}", e);
+            }
+        }
+    }
+
+    /// Some -> account is locked until the returned time
+    pub fn locked_until(&self, account: &str) -> Option<u64> {
+        let now = totp::now();
+        self.state.lock().unwrap().locked.get(account).cloned().filter(|until| *until > now)
+    }
+
+    /// Counts a failed login, returns true if the account got locked by it.
+    pub fn record_failure(&self, account: &str) -> bool {
+        if self.policy.threshold == 0 {
+            return false;
+        }
+        let now = totp::now();
+        let mut state = self.state.lock().unwrap();
+        let locked = {
+            let failures = state.failures.entry(account.to_string())
+                .or_insert(Failures { count: 0, first: now });
+            if failures.first + self.policy.window < now {
+                failures.count = 0;
+                failures.first = now;
+            }
+            failures.count += 1;
+            failures.count >= self.policy.threshold
+        };
+        if locked {
+            let until = now + self.policy.duration;
+            warn!("Lock account {} until {} after {} failed logins", account, until,
+                  self.policy.threshold);
+            state.failures.remove(account);
+            state.locked.insert(account.to_string(), until);
+            self.journal_append(&[("op", "lock"), ("account", account), ("until", &until.to_string())]);
+        }
+        locked
+    }
+
+    pub fn record_success(&self, account: &str) {
+        self.state.lock().unwrap().failures.remove(account);
+    }
+
+    /// Removes the lock of `account`, returns false if it was not locked.
+    pub fn unlock(&self, account: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.failures.remove(account);
+        let unlocked = state.locked.remove(account).is_some();
+        if unlocked {
+            warn!("Unlock account {}", account);
+            self.journal_append(&[("op", "unlock"), ("account", account)]);
+        }
+        unlocked
+    }
+
+    /// (account, locked until) of all locked accounts
+    pub fn locked_accounts(&self) -> Vec<(String, u64)> {
+        let now = totp::now();
+        self.state.lock().unwrap().locked.iter()
+            .filter(|&(_, until)| *until > now)
+            .map(|(account, until)| (account.clone(), *until))
+            .collect()
+    }
+
+    /// Forgets expired locks and failures outside of the window, compacts the lock file.
+    pub fn clean_outdated(&self) {
+        let now = totp::now();
+        let mut state = self.state.lock().unwrap();
+        let window = self.policy.window;
+        state.failures.retain(|_, failures| failures.first + window >= now);
+        state.locked.retain(|_, until| *until > now);
+        if let Some(ref journal) = self.journal {
+            let locked: Vec<(&String, String)> = state.locked.iter()
+                .map(|(account, until)| (account, until.to_string()))
+                .collect();
+            let records = locked.iter().map(|&(account, ref until)| {
+                vec![("op", "lock"), ("account", account.as_str()), ("until", until.as_str())]
+            });
+            if let Err(e) = journal.lock().unwrap().rewrite(records) {
+                error!("Failed to compact lock file: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lock_after_threshold() {
+        let lockout = Lockout::new(LockoutPolicy { threshold: 3, window: 60, duration: 60 });
+        assert!(!lockout.record_failure("alice"));
+        assert!(!lockout.record_failure("alice"));
+        lockout.record_success("alice");
+        assert!(!lockout.record_failure("alice"));
+        assert!(!lockout.record_failure("alice"));
+        assert!(lockout.locked_until("alice").is_none());
+        assert!(lockout.record_failure("alice"));
+        assert!(lockout.locked_until("alice").is_some());
+        assert!(lockout.locked_until("bob").is_none());
+
+        assert!(lockout.unlock("alice"));
+        assert!(lockout.locked_until("alice").is_none());
+        assert!(!lockout.unlock("alice"));
+    }
+
+    #[test]
+    fn disabled_without_threshold() {
+        let lockout = Lockout::new(LockoutPolicy { threshold: 0, window: 60, duration: 60 });
+        for _ in 0..10 {
+            assert!(!lockout.record_failure("alice"));
+        }
+        assert!(lockout.locked_until("alice").is_none());
+    }
+}
@@ -1,13 +1,12 @@
-#![feature(test,integer_atomics,duration_as_u128)]
+#![feature(test)]
 use std::sync::Arc;
 use std::thread;
 use std::sync::atomic;
-use std::net::SocketAddr;
+use std::path::PathBuf;
 
 #[macro_use]
 extern crate log;
 extern crate tokio;
-extern crate tokio_threadpool;
 extern crate tokio_executor;
 extern crate tokio_signal;
 extern crate futures;
@@ -18,7 +17,7 @@ extern crate evmap;
 extern crate test;
 #[macro_use]
 extern crate horrorshow;
-extern crate random;
+extern crate getrandom;
 extern crate http;
 extern crate httparse;
 extern crate bytes;
@@ -26,38 +25,168 @@ extern crate thread_local;
 extern crate cookie;
 extern crate url;
 extern crate structopt;
+extern crate base32;
+extern crate sha2;
+extern crate hmac;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+extern crate libc;
 
 use structopt::StructOpt;
 use log::LogLevel::{Debug, Warn};
-use time::Duration;
 use futures::{Future, Stream};
-use tokio_threadpool::Builder;
+use tokio::runtime::Builder;
 use tokio_executor::enter;
+use tokio_signal::unix::{Signal, SIGTERM};
 
 mod request_handler;
+mod cookie_config;
 mod cookie_store;
+mod journal;
+mod lockout;
+mod otp_store;
 mod http_server;
+mod proxy;
+mod redirect;
 mod router;
-mod system;
+mod session_binding;
+mod signed_cookie;
+mod throttle;
 mod totp;
+mod unix_socket;
+mod users;
 
-use cookie_store::CookieStore;
+use cookie_config::{CookieConfig, parse_cookie_name, parse_same_site};
+use cookie_store::{CookieStore, SessionLifetime};
+use otp_store::OtpStore;
+use users::Users;
+use signed_cookie::CookieSigner;
+use throttle::Throttle;
+use lockout::{Lockout, LockoutPolicy};
+use proxy::{Cidr, TrustedProxies};
+use http_server::{Listen, ServerConfig};
+use redirect::{AllowedTarget, RedirectPolicy};
+use session_binding::BindingPolicy;
+use unix_socket::{SocketOwner, parse_mode};
 
 #[derive(Clone)]
 pub struct ApplicationState {
     cookie_store: CookieStore,
-    cookie_max_age: Duration,
+    otp_store: OtpStore,
+    users: Option<Arc<Users>>,
+    cookie_signer: Option<CookieSigner>,
+    session_lifetime: SessionLifetime,
+    cookie_config: CookieConfig,
+    redirect_policy: RedirectPolicy,
     debug: bool,
-    request_slowdown: Arc<atomic::AtomicU64>,
+    throttle: Throttle,
+    lockout: Lockout,
+    admin_group: String,
+    session_binding: BindingPolicy,
 }
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "nginx-auth-totp")]
 struct Opt {
-    #[structopt(short = "l", long = "port", default_value = "127.0.0.1:8080")]
-    addr: SocketAddr,
+    /// Address to listen on, host:port or unix:/path for a Unix socket
+    #[structopt(short = "l", long = "listen", default_value = "127.0.0.1:8080", raw(alias = r#""port""#))]
+    listen: Listen,
+    /// File mode of the Unix socket, in octal
+    #[structopt(long = "listen-mode", default_value = "660", parse(try_from_str = "parse_mode"))]
+    listen_mode: u32,
+    /// Owner of the Unix socket as user, user:group or :group
+    #[structopt(long = "listen-owner")]
+    listen_owner: Option<SocketOwner>,
     #[structopt(short = "d", long = "debug")]
     debug: bool,
+    /// File to persist HOTP counters in
+    #[structopt(long = "hotp-state-file", parse(from_os_str))]
+    hotp_state_file: Option<PathBuf>,
+    /// TOML file with users and their secrets, replaces the X-Totp-Secret header
+    #[structopt(long = "users-file", parse(from_os_str))]
+    users_file: Option<PathBuf>,
+    /// File to persist sessions in, such that they survive a restart
+    #[structopt(long = "session-file", parse(from_os_str))]
+    session_file: Option<PathBuf>,
+    /// Seconds a session stays valid without being used, every use extends it
+    #[structopt(long = "session-idle-timeout", default_value = "86400")]
+    session_idle_timeout: u64,
+    /// Seconds after login a session ends regardless of use
+    #[structopt(long = "session-max-lifetime", default_value = "604800")]
+    session_max_lifetime: u64,
+    /// Name of the session cookie
+    #[structopt(long = "cookie-name", default_value = "totp_cookie", parse(try_from_str = "parse_cookie_name"))]
+    cookie_name: String,
+    /// Domain of the session cookie, to share it with subdomains
+    #[structopt(long = "cookie-domain")]
+    cookie_domain: Option<String>,
+    /// Path of the session cookie
+    #[structopt(long = "cookie-path", default_value = "/")]
+    cookie_path: String,
+    /// Only send the session cookie over HTTPS
+    #[structopt(long = "cookie-secure")]
+    cookie_secure: bool,
+    /// SameSite attribute of the session cookie: strict or lax
+    #[structopt(long = "cookie-same-site", default_value = "lax", parse(try_from_str = "parse_same_site"))]
+    cookie_same_site: cookie::SameSite,
+    /// Host, with optional port and path prefix, that may be redirected to after login, like
+    /// app.example.org or example.org:8443/wiki. May be given multiple times, paths on the
+    /// same host are always allowed
+    #[structopt(long = "redirect-allow")]
+    redirect_allow: Vec<AllowedTarget>,
+    /// Page to go to after login if no or a not allowed redirect is requested
+    #[structopt(long = "default-redirect", default_value = "/")]
+    default_redirect: String,
+    /// Maximum number of sessions, the oldest sessions are removed when exceeded
+    #[structopt(long = "max-sessions", default_value = "100000")]
+    max_sessions: usize,
+    /// File with hex encoded keys to sign stateless session cookies with, one per line.
+    /// The first key signs, all keys are accepted.
+    #[structopt(long = "cookie-key-file", parse(from_os_str))]
+    cookie_key_file: Option<PathBuf>,
+    /// Lock an account of the users file after this many consecutive failed logins, 0
+    /// disables locking
+    #[structopt(long = "lockout-threshold", default_value = "0")]
+    lockout_threshold: u32,
+    /// Seconds within which failed logins count towards the lockout threshold
+    #[structopt(long = "lockout-window", default_value = "900")]
+    lockout_window: u64,
+    /// Seconds an account stays locked
+    #[structopt(long = "lockout-duration", default_value = "3600")]
+    lockout_duration: u64,
+    /// Members of this group may unlock accounts at /unlock
+    #[structopt(long = "admin-group", default_value = "admin")]
+    admin_group: String,
+    /// Proxy whose X-Real-IP, Forwarded and X-Forwarded-For headers are trusted, as address
+    /// or CIDR network. May be given multiple times, defaults to 127.0.0.1 and ::1
+    #[structopt(long = "trusted-proxy")]
+    trusted_proxies: Vec<Cidr>,
+    /// Seconds a persistent connection may wait for the next request
+    #[structopt(long = "keep-alive-timeout", default_value = "75")]
+    keep_alive_timeout: u64,
+    /// Requests served on one connection before it is closed
+    #[structopt(long = "max-requests-per-connection", default_value = "1000")]
+    max_requests_per_connection: usize,
+    /// Largest request body accepted, in bytes
+    #[structopt(long = "max-body-size", default_value = "16384")]
+    max_body_size: usize,
+    /// Most headers accepted in a request
+    #[structopt(long = "max-headers", default_value = "100")]
+    max_headers: usize,
+    /// Bind sessions to the network of the client address at login
+    #[structopt(long = "bind-ip")]
+    bind_ip: bool,
+    /// Prefix length of the network IPv4 clients are bound to with --bind-ip
+    #[structopt(long = "bind-ipv4-prefix", default_value = "32")]
+    bind_ipv4_prefix: u8,
+    /// Prefix length of the network IPv6 clients are bound to with --bind-ip
+    #[structopt(long = "bind-ipv6-prefix", default_value = "64")]
+    bind_ipv6_prefix: u8,
+    /// Bind sessions to the User-Agent at login
+    #[structopt(long = "bind-user-agent")]
+    bind_user_agent: bool,
 }
 
 fn main() {
@@ -67,11 +196,76 @@ fn main() {
     debug!("If you read this message then we're running debug (-d) mode.");
     debug!("Debug mode is not safe for public accesible instances");
 
+    let otp_store = match opt.hotp_state_file {
+        Some(ref path) => OtpStore::with_hotp_state_file(path)
+            .unwrap_or_else(|e| panic!("Failed to load HOTP state file {}: {}", path.display(), e)),
+        None => OtpStore::new(),
+    };
+
+    let users = opt.users_file.as_ref().map(|path| {
+        let users = Users::load(path).unwrap_or_else(|e| panic!("Failed to load users: {}", e));
+        info!("Loaded {} users from {}", users.len(), path.display());
+        Arc::new(users)
+    });
+
+    let cookie_store = match opt.session_file {
+        Some(ref path) => CookieStore::with_session_file(path)
+            .unwrap_or_else(|e| panic!("Failed to load session file {}: {}", path.display(), e)),
+        None => CookieStore::new(),
+    }.limit_sessions(opt.max_sessions);
+
+    let cookie_signer = opt.cookie_key_file.as_ref().map(|path| {
+        CookieSigner::load(path).unwrap_or_else(|e| panic!("Failed to load cookie keys: {}", e))
+    });
+
+    if opt.lockout_threshold > 0 && opt.users_file.is_none() {
+        warn!("--lockout-threshold only applies to users of --users-file");
+    }
+    let lockout_policy = LockoutPolicy {
+        threshold: opt.lockout_threshold,
+        window: opt.lockout_window,
+        duration: opt.lockout_duration,
+    };
+    // locks are persisted next to the sessions
+    let lockout = match opt.session_file {
+        Some(ref path) => {
+            let mut lock_file = path.clone().into_os_string();
+            lock_file.push(".locks");
+            let lock_file = PathBuf::from(lock_file);
+            Lockout::with_lock_file(lockout_policy, &lock_file)
+                .unwrap_or_else(|e| panic!("Failed to load lock file {}: {}", lock_file.display(), e))
+        }
+        None => Lockout::new(lockout_policy),
+    };
+
     let state = ApplicationState {
-        cookie_store: CookieStore::new(),
-        cookie_max_age: Duration::days(1),
+        cookie_store,
+        otp_store,
+        users,
+        cookie_signer,
+        session_lifetime: SessionLifetime {
+            idle_timeout: opt.session_idle_timeout,
+            max_lifetime: opt.session_max_lifetime,
+        },
+        cookie_config: CookieConfig {
+            name: opt.cookie_name.clone(),
+            domain: opt.cookie_domain.clone(),
+            path: opt.cookie_path.clone(),
+            secure: opt.cookie_secure,
+            same_site: opt.cookie_same_site,
+        },
+        redirect_policy: RedirectPolicy {
+            allowed: opt.redirect_allow.clone(),
+            default: opt.default_redirect.clone(),
+        },
         debug: opt.debug,
-        request_slowdown: Arc::new(atomic::AtomicU64::new(0)),
+        throttle: Throttle::new(),
+        lockout,
+        admin_group: opt.admin_group.clone(),
+        session_binding: BindingPolicy {
+            ip_prefixes: if opt.bind_ip { Some((opt.bind_ipv4_prefix, opt.bind_ipv6_prefix)) } else { None },
+            user_agent: opt.bind_user_agent,
+        },
     };
 
     let server_shutdown_condvar = Arc::new(atomic::AtomicBool::new(false));
@@ -82,35 +276,59 @@ fn main() {
         thread::spawn(move || {
             thread::park_timeout(std::time::Duration::from_secs(10));
             while !server_shutdown_condvar.load(atomic::Ordering::Relaxed) {
-                info!("Clean cookie cache");
-                state.cookie_store.clean_outdated_cookies();
+                let removed = state.cookie_store.clean_outdated_cookies();
+                info!("Clean cookie cache, removed {} outdated cookies", removed);
+                state.cookie_store.compact_session_file();
+                state.otp_store.clean_outdated_steps();
+                state.throttle.clean_idle();
+                state.lockout.clean_outdated();
                 thread::park_timeout(std::time::Duration::from_secs(60));
             }
         })
     };
 
     let request_handler = request_handler::RequestHandler::make();
-    let runtime = Builder::new()
+    // the tokio runtime provides the timer needed for the keep-alive timeout
+    let mut runtime = Builder::new()
         .name_prefix("httpd-")
         .after_start(|| {
             debug!("Start new worker: {}", thread::current().name().unwrap_or("-"));
-            system::initialize_rng_from_time();
         })
-        .build();
+        .build()
+        .unwrap_or_else(|e| panic!("Failed to start runtime: {}", e));
+
+    let server_config = ServerConfig {
+        trusted_proxies: if opt.trusted_proxies.is_empty() {
+            TrustedProxies::default()
+        } else {
+            TrustedProxies::new(opt.trusted_proxies.clone())
+        },
+        keep_alive_timeout: std::time::Duration::from_secs(opt.keep_alive_timeout),
+        max_requests_per_connection: opt.max_requests_per_connection,
+        max_body_size: opt.max_body_size,
+        max_headers: opt.max_headers,
+        socket_mode: opt.listen_mode,
+        socket_owner: opt.listen_owner,
+    };
 
-    let program = http_server::serve(opt.addr, state, request_handler);
+    let program = http_server::serve(&opt.listen, server_config, state, request_handler);
     runtime.spawn(program);
 
-    let ctrl_c_block = tokio_signal::ctrl_c()
-        .flatten_stream().take(1).for_each(|()| {
-        info!("ctrl-c received");
+    // ctrl-c, or SIGTERM as sent by service managers
+    let ctrl_c = tokio_signal::ctrl_c().flatten_stream().map(|()| "ctrl-c");
+    let sigterm = Signal::new(SIGTERM).flatten_stream().map(|_| "SIGTERM");
+    let shutdown_block = ctrl_c.select(sigterm).take(1).for_each(|signal| {
+        info!("{} received", signal);
         Ok(())
     });
 
     enter().expect("nested tokio::run")
-        .block_on(ctrl_c_block)
+        .block_on(shutdown_block)
         .unwrap();
-    runtime.shutdown();
+    runtime.shutdown_now().wait().unwrap();
+    if let Listen::Unix(ref path) = opt.listen {
+        unix_socket::remove(path);
+    }
 
     info!("Waiting for cookie cleanup thread to stop");
     server_shutdown_condvar.store(true, atomic::Ordering::Relaxed);
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use sha2::{Digest, Sha256};
+
+use totp;
+
+struct UsedStep {
+    step: u64,
+    expires: u64,
+}
+
+/// Remembers per secret the last time-step a token was accepted for, such that a token
+/// cannot be replayed while it is still within its window.
+///
+/// For HOTP secrets the next expected counter value is kept instead. These counters are
+/// written to the state file (if configured) on every change, such that a restart does not
+/// allow reusing tokens. The file holds one line `<sha256 of the key> <counter>` per secret.
+#[derive(Clone)]
+pub struct OtpStore {
+    totp_steps: Arc<Mutex<HashMap<Vec<u8>, UsedStep>>>,
+    hotp_counters: Arc<Mutex<HashMap<String, u64>>>,
+    hotp_state_file: Option<PathBuf>,
+}
+
+impl OtpStore {
+    pub fn new() -> OtpStore {
+        OtpStore {
+            totp_steps: Arc::new(Mutex::new(HashMap::new())),
+            hotp_counters: Arc::new(Mutex::new(HashMap::new())),
+            hotp_state_file: None,
+        }
+    }
+
+    /// Like `new` but persists HOTP counters to `path`, loading the existing ones from it.
+    pub fn with_hotp_state_file(path: &Path) -> io::Result<OtpStore> {
+        let mut counters = HashMap::new();
+        match fs::File::open(path) {
+            Ok(file) => for line in io::BufReader::new(file).lines() {
+                let line = line?;
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next().and_then(|c| c.parse().ok())) {
+                    (Some(key_id), Some(counter)) => {
+                        counters.insert(key_id.to_string(), counter);
+                    }
+                    _ => warn!("Skip malformed line in {}: {}", path.display(), line),
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        info!("Loaded {} HOTP counters from {}", counters.len(), path.display());
+
+        let mut store = OtpStore::new();
+        store.hotp_counters = Arc::new(Mutex::new(counters));
+        store.hotp_state_file = Some(path.to_path_buf());
+        Ok(store)
+    }
+
+    /// true -> token is valid and now marked as used
+    /// false -> token is invalid or was used before
+    pub fn verify(&self, secret: &totp::Secret, token: &str) -> bool {
+        self.verify_at(secret, token, totp::now())
+    }
+
+    /// `verify` at the time `now` (seconds since epoch)
+    fn verify_at(&self, secret: &totp::Secret, token: &str, now: u64) -> bool {
+        match secret.kind {
+            totp::Kind::Totp => self.verify_totp(secret, token, now),
+            totp::Kind::Hotp => self.verify_hotp(secret, token),
+        }
+    }
+
+    fn verify_totp(&self, secret: &totp::Secret, token: &str, now: u64) -> bool {
+        let mut steps = self.totp_steps.lock().unwrap();
+        let last_used_step = steps.get(&secret.key).map(|used| used.step);
+        match totp::verify_at(secret, token, last_used_step, now) {
+            Some(step) => {
+                steps.insert(secret.key.clone(), UsedStep {
+                    step,
+                    expires: totp::step_expiry(secret, step),
+                });
+                true
+            }
+            None => {
+                if last_used_step.is_some() && totp::verify_at(secret, token, None, now).is_some() {
+                    warn!("Rejected replay of an already used token");
+                }
+                false
+            }
+        }
+    }
+
+    fn verify_hotp(&self, secret: &totp::Secret, token: &str) -> bool {
+        let key_id = format!("{:x}", Sha256::digest(&secret.key));
+        let mut counters = self.hotp_counters.lock().unwrap();
+        let next_counter = counters.get(&key_id).cloned().unwrap_or(0).max(secret.counter);
+        let counter = match totp::verify_hotp(secret, token, next_counter) {
+            Some(counter) => counter,
+            None => return false,
+        };
+        counters.insert(key_id, counter.saturating_add(1));
+        if let Err(e) = self.write_hotp_state_file(&counters) {
+            // rather fail the login than risk the token to be accepted again after a restart
+            error!("Failed to write HOTP state file: {}", e);
+            return false;
+        }
+        true
+    }
+
+    fn write_hotp_state_file(&self, counters: &HashMap<String, u64>) -> io::Result<()> {
+        let path = match self.hotp_state_file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = io::BufWriter::new(fs::File::create(&tmp_path)?);
+            for (key_id, counter) in counters {
+                writeln!(file, "{} {}", key_id, counter)?;
+            }
+            file.into_inner()?.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Forget used time-steps that are outside of every accepted window by now.
+    pub fn clean_outdated_steps(&self) {
+        let now = totp::now();
+        self.totp_steps.lock().unwrap().retain(|_, used| used.expires >= now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reject_replayed_token() {
+        // RFC 6238 test vector for SHA512 at 59s
+        let secret: totp::Secret = "hex:3132333435363738393031323334353637383930313233343536373839303132\
+                                    3334353637383930313233343536373839303132333435363738393031323334;digits=8"
+            .parse().unwrap();
+        let store = OtpStore::new();
+        assert!(store.verify_at(&secret, "90693936", 59));
+        assert!(!store.verify_at(&secret, "90693936", 59));
+        // still within the skew, but used
+        assert!(!store.verify_at(&secret, "90693936", 89));
+        // a token of a later step is accepted
+        assert!(store.verify_at(&secret, "25091201", 1111111109));
+    }
+}
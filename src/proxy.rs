@@ -0,0 +1,293 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use http::HeaderMap;
+
+static HTTP_HEADER_X_REAL_IP: &'static str = r"X-Real-IP";
+static HTTP_HEADER_X_FORWARDED_FOR: &'static str = r"X-Forwarded-For";
+static HTTP_HEADER_FORWARDED: &'static str = r"Forwarded";
+
+/// Address of the client as seen through trusted proxies, stored in the request extensions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// A network in CIDR notation like `10.0.0.0/8` or `fd00::/8`, a plain address
+/// is a network of just that host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Network of the first `prefix` bits of `addr`, the prefix is cut to the address length.
+    /// IPv4-mapped addresses are taken as IPv4 addresses, `prefix` then counts IPv4 bits.
+    pub fn new(addr: IpAddr, prefix: u8) -> Cidr {
+        let addr = unmap(addr);
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        Cidr { addr, prefix: prefix.min(bits) }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, unmap(*ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) =>
+                prefix_matches(u32::from(net) as u128, u32::from(ip) as u128, 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) =>
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix;
+    net >> shift == ip >> shift
+}
+
+/// IPv4 clients of a server listening on IPv6 show up as `::ffff:a.b.c.d`
+pub fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            if s[..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff {
+                IpAddr::V4(Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8))
+            } else {
+                ip
+            }
+        }
+        ip => ip,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().unwrap_or("").parse::<IpAddr>()
+            .map_err(|e| format!("invalid address in '{}': {}", s, e))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("invalid prefix length in '{}'", s))?,
+            None => bits,
+        };
+        if addr.is_ipv6() && unmap(addr).is_ipv4() {
+            Ok(Cidr::new(addr, prefix.saturating_sub(96)))
+        } else {
+            Ok(Cidr::new(addr, prefix))
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Proxies whose X-Real-IP, Forwarded and X-Forwarded-For headers are believed.
+#[derive(Clone, Debug)]
+pub struct TrustedProxies {
+    nets: Vec<Cidr>,
+}
+
+impl Default for TrustedProxies {
+    /// Trusts only a proxy on the same host
+    fn default() -> Self {
+        TrustedProxies {
+            nets: vec![
+                Cidr { addr: IpAddr::V4(Ipv4Addr::LOCALHOST), prefix: 32 },
+                Cidr { addr: IpAddr::V6(Ipv6Addr::LOCALHOST), prefix: 128 },
+            ],
+        }
+    }
+}
+
+impl TrustedProxies {
+    pub fn new(nets: Vec<Cidr>) -> TrustedProxies {
+        TrustedProxies { nets }
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+
+    /// Address of the client behind `peer`. Headers are only looked at if `peer` is a
+    /// trusted proxy.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = unmap(peer);
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+        self.forwarded_client_ip(headers).unwrap_or(peer)
+    }
+
+    /// Address of the client according to the headers of the proxy in front. X-Real-IP is
+    /// set by the proxy itself and preferred, unless it names another trusted proxy. Then,
+    /// or without X-Real-IP, the Forwarded or X-Forwarded-For chain is walked from the
+    /// right, skipping trusted proxies, up to the first address that is not trusted.
+    pub fn forwarded_client_ip(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        let real_ip = headers.get(HTTP_HEADER_X_REAL_IP)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_node);
+        if let Some(ip) = real_ip {
+            if !self.is_trusted(&ip) {
+                return Some(ip);
+            }
+        }
+        let chain: Vec<&str> = if headers.contains_key(HTTP_HEADER_FORWARDED) {
+            header_list(headers, HTTP_HEADER_FORWARDED).into_iter()
+                .map(forwarded_for)
+                .collect()
+        } else {
+            header_list(headers, HTTP_HEADER_X_FORWARDED_FOR)
+        };
+        let mut client = real_ip;
+        for node in chain.into_iter().rev() {
+            match parse_node(node) {
+                Some(ip) => {
+                    client = Some(ip);
+                    if !self.is_trusted(&ip) {
+                        break;
+                    }
+                }
+                // obfuscated or unknown hop, the last proxy is as far as we can tell
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// Comma separated elements of all occurrences of header `name`
+fn header_list<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+    headers.get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|element| element.trim())
+        .collect()
+}
+
+/// The `for` parameter of a Forwarded element like `for=192.0.2.60;proto=http`
+fn forwarded_for(element: &str) -> &str {
+    element.split(';')
+        .filter_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("for") =>
+                    Some(value.trim().trim_matches('"')),
+                _ => None,
+            }
+        })
+        .next()
+        .unwrap_or("")
+}
+
+/// Parses `192.0.2.60`, `192.0.2.60:4711`, `2001:db8::1` and `[2001:db8::1]:4711`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(unmap(ip));
+    }
+    let host = if node.starts_with('[') {
+        &node[1..node.find(']')?]
+    } else {
+        node.rsplitn(2, ':').last()?
+    };
+    host.parse::<IpAddr>().ok().map(unmap)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::header::HeaderValue;
+
+    fn headers(list: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in list {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(&ip("10.1.2.3")));
+        assert!(net.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(&ip("10.2.0.1")));
+        assert!(!net.contains(&ip("fd00::1")));
+        let net: Cidr = "fd00::/8".parse().unwrap();
+        assert!(net.contains(&ip("fd12::1")));
+        assert!(!net.contains(&ip("fe80::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(&ip("192.0.2.1")));
+        assert_eq!("192.0.2.1".parse::<Cidr>().unwrap().to_string(), "192.0.2.1/32");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let proxies = TrustedProxies::default();
+        let h = headers(&[("X-Real-IP", "192.0.2.1"), ("X-Forwarded-For", "192.0.2.2")]);
+        assert_eq!(proxies.client_ip(ip("198.51.100.7"), &h), ip("198.51.100.7"));
+        assert_eq!(proxies.client_ip(ip("127.0.0.1"), &h), ip("192.0.2.1"));
+        assert_eq!(proxies.client_ip(ip("127.0.0.1"), &headers(&[])), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn forwarded_client_without_peer_address() {
+        let proxies = TrustedProxies::default();
+        assert_eq!(proxies.forwarded_client_ip(&headers(&[("X-Real-IP", "192.0.2.1")])), Some(ip("192.0.2.1")));
+        assert_eq!(proxies.forwarded_client_ip(&headers(&[("X-Forwarded-For", "127.0.0.1")])), Some(ip("127.0.0.1")));
+        assert_eq!(proxies.forwarded_client_ip(&headers(&[("Forwarded", "for=unknown")])), None);
+        assert_eq!(proxies.forwarded_client_ip(&headers(&[])), None);
+    }
+
+    #[test]
+    fn behind_load_balancer() {
+        // load balancer 10.0.0.5 -> nginx on the same host -> server
+        let proxies = TrustedProxies::new(vec!["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()]);
+        let h = headers(&[("X-Real-IP", "10.0.0.5"), ("X-Forwarded-For", "192.0.2.9, 10.0.0.5")]);
+        assert_eq!(proxies.client_ip(ip("127.0.0.1"), &h), ip("192.0.2.9"));
+        let h = headers(&[("X-Real-IP", "10.0.0.5")]);
+        assert_eq!(proxies.client_ip(ip("127.0.0.1"), &h), ip("10.0.0.5"));
+        let h = headers(&[("X-Real-IP", "192.0.2.1"), ("X-Forwarded-For", "192.0.2.9")]);
+        assert_eq!(proxies.client_ip(ip("127.0.0.1"), &h), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn forwarded_for_chain() {
+        let proxies = TrustedProxies::new(vec!["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()]);
+        // the client made up the first entry, 10.0.0.5 is a trusted load balancer
+        let h = headers(&[("X-Forwarded-For", "1.1.1.1, 192.0.2.9"), ("X-Forwarded-For", "10.0.0.5")]);
+        assert_eq!(proxies.client_ip(ip("127.0.0.1"), &h), ip("192.0.2.9"));
+        let h = headers(&[("X-Forwarded-For", "10.0.0.6, 10.0.0.5")]);
+        assert_eq!(proxies.client_ip(ip("127.0.0.1"), &h), ip("10.0.0.6"));
+        let h = headers(&[("X-Forwarded-For", "192.0.2.9, garbage, 10.0.0.5")]);
+        assert_eq!(proxies.client_ip(ip("127.0.0.1"), &h), ip("10.0.0.5"));
+    }
+
+    #[test]
+    fn forwarded() {
+        let proxies = TrustedProxies::default();
+        let h = headers(&[("Forwarded", "for=192.0.2.60;proto=http;by=203.0.113.43"),
+                          ("X-Forwarded-For", "192.0.2.1")]);
+        assert_eq!(proxies.client_ip(ip("::1"), &h), ip("192.0.2.60"));
+        let h = headers(&[("Forwarded", r#"for="[2001:db8:cafe::17]:4711", For=127.0.0.1:80"#)]);
+        assert_eq!(proxies.client_ip(ip("127.0.0.1"), &h), ip("2001:db8:cafe::17"));
+        let h = headers(&[("Forwarded", "for=_hidden")]);
+        assert_eq!(proxies.client_ip(ip("127.0.0.1"), &h), ip("127.0.0.1"));
+    }
+}
@@ -0,0 +1,126 @@
+use std::str::FromStr;
+
+use url::Url;
+
+/// Host, with optional port and path prefix, that may be redirected to after login,
+/// like `app.example.org` or `example.org:8443/wiki`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AllowedTarget {
+    host: String,
+    port: Option<u16>,
+    path_prefix: String,
+}
+
+impl AllowedTarget {
+    fn matches(&self, url: &Url) -> bool {
+        let host_matches = url.host_str().map_or(false, |host| host.eq_ignore_ascii_case(&self.host));
+        let path = url.path();
+        let path_matches = path.starts_with(&self.path_prefix)
+            && (self.path_prefix.ends_with('/') || path.len() == self.path_prefix.len()
+                || path[self.path_prefix.len()..].starts_with('/'));
+        host_matches && url.port() == self.port && path_matches
+    }
+}
+
+impl FromStr for AllowedTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(&format!("http://{}", s))
+            .map_err(|e| format!("invalid redirect target '{}': {}", s, e))?;
+        if url.query().is_some() || url.fragment().is_some() || !url.username().is_empty() {
+            return Err(format!("redirect target '{}' must be host, port and path only", s));
+        }
+        Ok(AllowedTarget {
+            host: url.host_str().unwrap_or("").to_string(),
+            port: url.port(),
+            path_prefix: url.path().to_string(),
+        })
+    }
+}
+
+/// Decides where to send the browser after login. Paths on the same host are always
+/// allowed, absolute URLs only if they match an allowed target. Anything else is replaced
+/// by the default landing page.
+#[derive(Clone, Debug)]
+pub struct RedirectPolicy {
+    pub allowed: Vec<AllowedTarget>,
+    pub default: String,
+}
+
+impl RedirectPolicy {
+    pub fn target(&self, redirect: &str) -> String {
+        if self.is_allowed(redirect) {
+            redirect.to_string()
+        } else {
+            if !redirect.is_empty() {
+                warn!("Reject redirect to {:?}", redirect);
+            }
+            self.default.clone()
+        }
+    }
+
+    fn is_allowed(&self, redirect: &str) -> bool {
+        // browsers treat backslashes like slashes and ignore some control characters
+        if redirect.contains(|c: char| c == '\\' || c.is_control()) {
+            return false;
+        }
+        if redirect.starts_with('/') {
+            // "//host" would be relative to the scheme only
+            return !redirect.starts_with("//");
+        }
+        match Url::parse(redirect) {
+            Ok(url) => (url.scheme() == "https" || url.scheme() == "http")
+                && url.username().is_empty() && url.password().is_none()
+                && self.allowed.iter().any(|target| target.matches(&url)),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy() -> RedirectPolicy {
+        RedirectPolicy {
+            allowed: vec!["app.example.org".parse().unwrap(), "example.org:8443/wiki".parse().unwrap()],
+            default: "/".to_string(),
+        }
+    }
+
+    #[test]
+    fn relative_paths() {
+        let policy = policy();
+        assert_eq!(policy.target("/private/page?x=1"), "/private/page?x=1");
+        assert_eq!(policy.target("//evil.example"), "/");
+        assert_eq!(policy.target("/\\evil.example"), "/");
+        assert_eq!(policy.target("/\t/evil.example"), "/");
+        assert_eq!(policy.target(""), "/");
+        assert_eq!(policy.target("javascript:alert(1)"), "/");
+        assert_eq!(policy.target("evil.example"), "/");
+    }
+
+    #[test]
+    fn allowed_targets() {
+        let policy = policy();
+        assert_eq!(policy.target("https://app.example.org/x"), "https://app.example.org/x");
+        assert_eq!(policy.target("https://APP.example.org"), "https://APP.example.org");
+        assert_eq!(policy.target("https://app.example.org:444/"), "/");
+        assert_eq!(policy.target("https://user@app.example.org/"), "/");
+        assert_eq!(policy.target("https://app.example.org.evil.example/"), "/");
+        assert_eq!(policy.target("ftp://app.example.org/"), "/");
+        assert_eq!(policy.target("https://example.org:8443/wiki/Page"), "https://example.org:8443/wiki/Page");
+        assert_eq!(policy.target("https://example.org:8443/wiki"), "https://example.org:8443/wiki");
+        assert_eq!(policy.target("https://example.org:8443/wikifake"), "/");
+        assert_eq!(policy.target("https://example.org/wiki"), "/");
+    }
+
+    #[test]
+    fn parse_allowed_target() {
+        assert!("example.org/a?b".parse::<AllowedTarget>().is_err());
+        assert!("user@example.org".parse::<AllowedTarget>().is_err());
+        assert_eq!("Example.org".parse::<AllowedTarget>().unwrap(),
+                   AllowedTarget { host: "example.org".to_string(), port: None, path_prefix: "/".to_string() });
+    }
+}
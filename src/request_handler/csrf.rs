@@ -0,0 +1,76 @@
+use http::{Request, Response, StatusCode};
+use http::header::{HOST, ORIGIN, REFERER, SET_COOKIE};
+use http::response::Builder;
+use bytes::Bytes;
+use url::Url;
+use getrandom::getrandom;
+
+use ::ApplicationState;
+use super::{HeaderExtract, ResponseBuilderExtra};
+
+/// Form field carrying the CSRF token
+pub(in request_handler) static FORM_FIELD: &'static str = "csrf";
+
+/// The CSRF token to embed in forms. It is a random value that is also sent as cookie
+/// (double submit), a client without one gets a new token and the cookie is added to
+/// `response`.
+pub(in request_handler) fn token(header_infos: &HeaderExtract, state: &ApplicationState,
+                                 response: &mut Builder) -> String {
+    let name = state.cookie_config.csrf_name();
+    if let Some(cookie) = header_infos.cookies.iter().find(|cookie| cookie.name() == name) {
+        if !cookie.value().is_empty() {
+            return cookie.value().to_string();
+        }
+    }
+    let mut bytes = [0; 32];
+    getrandom(&mut bytes).expect("Failed to read random bytes from the OS");
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    response.header(SET_COOKIE, state.cookie_config.csrf_cookie(token.clone()).to_string().as_str());
+    token
+}
+
+/// Checks a state changing request: it must come from a page of this server according to
+/// Origin or Referer, if given, and the token of the form must match the cookie.
+pub(in request_handler) fn verify(req: &Request<Bytes>, header_infos: &HeaderExtract, state: &ApplicationState,
+                                  form_token: Option<&str>) -> Result<(), String> {
+    let source = req.headers().get(ORIGIN)
+        .or_else(|| req.headers().get(REFERER))
+        .map(|value| value.to_str().unwrap_or(""));
+    if let Some(source) = source {
+        let host = req.headers().get(HOST)
+            .and_then(|value| value.to_str().ok())
+            .ok_or("request without Host header")?;
+        let url = Url::parse(source).map_err(|_| format!("invalid origin {:?}", source))?;
+        let authority = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+            None => url.host_str().unwrap_or("").to_string(),
+        };
+        if !authority.eq_ignore_ascii_case(host) {
+            return Err(format!("origin {} does not match host {}", source, host));
+        }
+    }
+
+    let name = state.cookie_config.csrf_name();
+    let cookie_token = header_infos.cookies.iter()
+        .find(|cookie| cookie.name() == name)
+        .map(|cookie| cookie.value())
+        .filter(|token| !token.is_empty())
+        .ok_or("no CSRF cookie")?;
+    let form_token = form_token.ok_or("no CSRF token in form")?;
+    if !constant_time_eq(cookie_token.as_bytes(), form_token.as_bytes()) {
+        return Err("CSRF token does not match cookie".to_string());
+    }
+    Ok(())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub(in request_handler) fn rejected(reason: String) -> Response<String> {
+    warn!("Reject request: {}", reason);
+    Response::builder()
+        .set_defaults()
+        .status(StatusCode::FORBIDDEN)
+        .body("Request rejected, reload the page and try again".to_string()).unwrap()
+}
@@ -2,98 +2,222 @@ use std::ops::Add;
 use std::io;
 use std::borrow::Cow;
 use std::time;
-use std::thread;
 use std::sync::atomic;
 
 use tokio::prelude::*;
 
 use http::{Request, Response, StatusCode, Method};
-use http::header::{SET_COOKIE, COOKIE};
+use http::header::{SET_COOKIE, COOKIE, RETRY_AFTER};
 use url::form_urlencoded;
+use sha2::{Digest, Sha256};
 
 use ::ApplicationState;
 use ::totp;
+use ::otp_store::OtpStore;
+use ::cookie_store::Identity;
+use ::throttle::ThrottleKey;
+use ::users::Users;
 use super::*;
 
 pub(in super) fn GET<'a>(header_infos: &HeaderExtract, state: &ApplicationState, path_rest: &'a str)
                          -> Response<String> {
-    if is_logged_in(&header_infos.cookies, &state.cookie_store) {
-        Response::builder().set_defaults().body(views::login_is_logged_in()).unwrap()
+    let mut response = Response::builder();
+    response.set_defaults();
+    let csrf_token = csrf::token(header_infos, state, &mut response);
+    if is_logged_in(header_infos, state) {
+        response.body(views::login_is_logged_in(csrf_token)).unwrap()
     } else {
-        Response::builder().set_defaults().body(views::login_login_form(path_rest)).unwrap()
+        response.body(views::login_login_form(path_rest, state.users.is_some(), csrf_token)).unwrap()
     }
 }
 
-fn test_secrets(secrets: &Vec<&str>, token: &String) -> bool {
+fn test_secrets(otp_store: &OtpStore, secrets: &Vec<totp::Secret>, token: &String) -> bool {
     secrets.iter()
-        .any(|secret| {
-            match totp::verify(secret, token) {
-                Ok(true) => true,
-                Ok(false) => false,
-                Err(e) => {
-                    error!("Error from totp::verify: {}", e);
-                    false
-                }
+        .any(|secret| otp_store.verify(secret, token))
+}
+
+/// The account a login attempt is for: the user name or, with secrets from headers, a
+/// fingerprint of the secrets. Without users file the user name is whatever the client
+/// sent, so it is ignored.
+fn account(users: &Option<Arc<Users>>, username: &Option<String>, secrets: &Vec<totp::Secret>) -> String {
+    match (users, username) {
+        (&Some(_), &Some(ref username)) => username.clone(),
+        _ => {
+            let mut hasher = Sha256::new();
+            for secret in secrets {
+                hasher.input(&secret.key);
             }
-        })
+            let fingerprint = format!("{:x}", hasher.result());
+            format!("secrets-{}", &fingerprint[..16])
+        }
+    }
+}
+
+/// Failed attempts are counted per client address and per account of the users file. With
+/// secrets from headers every client has the same account, a failure would slow down all
+/// of them, so it only counts if the client address is unknown.
+fn throttle_keys(header_infos: &HeaderExtract, account: &str, user_account: bool) -> Vec<ThrottleKey> {
+    let mut keys = Vec::new();
+    if let Some(ip) = header_infos.client_ip {
+        keys.push(ThrottleKey::Client(ip));
+    }
+    if user_account || keys.is_empty() {
+        keys.push(ThrottleKey::Account(account.to_string()));
+    }
+    keys
+}
+
+fn locked_response(locked_until: u64) -> Response<String> {
+    Response::builder()
+        .set_defaults()
+        .status(StatusCode::FORBIDDEN)
+        .body(views::login_locked(format_time(locked_until))).unwrap()
 }
 
 pub(in super) fn POST<'a>(header_infos: &HeaderExtract, state: &ApplicationState, req: &Request<Bytes>)
                           -> Response<String> {
-let wait_until = state.request_slowdown.load(atomic::Ordering::Acquire);
-    let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
-
-    let slept = if wait_until > now {
-        let time = wait_until - now;
-        warn!("Sleep {}s", time);
-        thread::sleep(time::Duration::from_secs(time));
-        time
-    } else {
-        0
-    };
-
     let mut token = None;
     let mut redirect = None;
+    let mut username = None;
+    let mut csrf_token = None;
     for (key, val) in form_urlencoded::parse(req.body()) {
         if key == "token" {
             token = Some(val.into_owned())
         } else if key == "redirect" {
             redirect = Some(val.into_owned())
+        } else if key == "username" {
+            username = Some(val.into_owned())
+        } else if key == csrf::FORM_FIELD {
+            csrf_token = Some(val.into_owned())
         }
     }
+    // checked before anything else, a forged login must not count as failed attempt
+    if let Err(reason) = csrf::verify(req, header_infos, state, csrf_token.as_ref().map(|t| t.as_str())) {
+        return csrf::rejected(reason);
+    }
     if token.is_none() {
         return error_handler_internal("missing argument 'token'".to_string());
     }
-    let redirect = redirect.unwrap_or(Default::default());
+    let redirect = state.redirect_policy.target(&redirect.unwrap_or_default());
 
-    if header_infos.totp_secrets.is_empty() {
+    let no_secrets = Vec::new();
+    let (user, secrets) = match state.users {
+        Some(ref users) => {
+            let username = match username {
+                Some(ref username) => username,
+                None => return error_handler_internal("missing argument 'username'".to_string()),
+            };
+            match users.get(username) {
+                Some(user) => (Some(user), &user.secrets),
+                None => {
+                    // handled like a wrong token to not reveal which users exist
+                    warn!("Login attempt for unknown user {}", username);
+                    (None, &no_secrets)
+                }
+            }
+        }
+        None => (None, &header_infos.totp_secrets),
+    };
+
+    if state.users.is_none() && secrets.is_empty() {
         return error_handler_internal("no secrets configured".to_string());
     }
 
-    if test_secrets(&header_infos.totp_secrets, &token.unwrap()) {
-        let cookie_value = state.cookie_store.create_authenticated_cookie();
-        let cookie = CookieBuilder::new(COOKIE_NAME, cookie_value.to_string())
-            .http_only(true)
-            .path("/")
-            .max_age(state.cookie_max_age)
-            .finish();
-        warn!("Authenticated user with cookie {}", cookie);
+    let account = account(&state.users, &username, &header_infos.totp_secrets);
+    // with secrets from headers all clients share the account, nobody could unlock it
+    let lockout_account = if state.users.is_some() { Some(&account) } else { None };
+    if let Some(locked_until) = lockout_account.and_then(|account| state.lockout.locked_until(account)) {
+        warn!("Reject login attempt for locked account {}", account);
+        return locked_response(locked_until);
+    }
+
+    let throttle_keys = throttle_keys(header_infos, &account, state.users.is_some());
+    let wait_time = state.throttle.wait_time(&throttle_keys);
+    if wait_time > 0 {
+        // answered right away, sleeping here would block a worker thread of the server
+        warn!("Reject login attempt, retry after {}s", wait_time);
+        return Response::builder()
+            .set_defaults()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(RETRY_AFTER, wait_time.to_string().as_str())
+            .body(views::login_too_many_attempts(wait_time)).unwrap();
+    }
+
+    if test_secrets(&state.otp_store, secrets, &token.unwrap()) {
+        state.throttle.record_success(&throttle_keys);
+        if let Some(account) = lockout_account {
+            state.lockout.record_success(account);
+        }
+        let identity = match user {
+            Some(user) => {
+                info!("Authenticated user {}", user.name);
+                Identity { account, username: Some(user.name.clone()), groups: user.groups.clone() }
+            }
+            None => Identity { account, ..Identity::default() },
+        };
+        let cookie = create_session(header_infos, state, identity);
         Response::builder()
             .set_defaults()
             .header(SET_COOKIE, cookie.to_string())
             .body(views::login_auth_success(&redirect)).unwrap()
     } else {
-        let current_wait = state.request_slowdown.load(atomic::Ordering::Acquire);
-        let wait_until = time::SystemTime::now()
-            .add(time::Duration::from_secs(8))
-            .duration_since(time::UNIX_EPOCH).unwrap()
-            .as_secs();
-        // if this request was already delayed then we double-delay
-        let wait_until = wait_until.max(current_wait + 8 + slept);
-        state.request_slowdown.store(wait_until, atomic::Ordering::Release);
+        state.throttle.record_failure(&throttle_keys);
+        if let Some(account) = lockout_account {
+            if state.lockout.record_failure(account) {
+                if let Some(locked_until) = state.lockout.locked_until(account) {
+                    return locked_response(locked_until);
+                }
+            }
+        }
 
         Response::builder()
             .set_defaults()
             .body(views::login_auth_fail()).unwrap()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::throttle::Throttle;
+
+    fn header_infos(client_ip: &str) -> HeaderExtract<'static> {
+        HeaderExtract {
+            totp_secrets: vec!["deadc0de".parse().unwrap()],
+            cookies: Vec::new(),
+            client_ip: Some(client_ip.parse().unwrap()),
+            user_agent: None,
+        }
+    }
+
+    #[test]
+    fn header_secrets_account_ignores_username() {
+        let attacker = header_infos("192.0.2.1");
+        let alice = account(&None, &Some("alice".to_string()), &attacker.totp_secrets);
+        let bob = account(&None, &Some("bob".to_string()), &attacker.totp_secrets);
+        assert_eq!(alice, bob);
+        assert!(alice.starts_with("secrets-"));
+    }
+
+    #[test]
+    fn header_secrets_throttle_per_client() {
+        let throttle = Throttle::new();
+        let attacker = header_infos("192.0.2.1");
+        let account = account(&None, &None, &attacker.totp_secrets);
+        throttle.record_failure(&throttle_keys(&attacker, &account, false));
+        assert!(throttle.wait_time(&throttle_keys(&attacker, &account, false)) > 0);
+        // other clients of the same secrets are not held up
+        assert_eq!(throttle.wait_time(&throttle_keys(&header_infos("198.51.100.1"), &account, false)), 0);
+
+        let unknown = HeaderExtract { client_ip: None, ..header_infos("192.0.2.1") };
+        assert_eq!(throttle_keys(&unknown, &account, false), vec![ThrottleKey::Account(account.clone())]);
+    }
+
+    #[test]
+    fn users_file_throttle_per_account() {
+        let throttle = Throttle::new();
+        throttle.record_failure(&throttle_keys(&header_infos("192.0.2.1"), "alice", true));
+        assert!(throttle.wait_time(&throttle_keys(&header_infos("198.51.100.1"), "alice", true)) > 0);
+        assert_eq!(throttle.wait_time(&throttle_keys(&header_infos("198.51.100.1"), "bob", true)), 0);
+    }
+}
@@ -9,6 +9,7 @@ use std::str;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock, Mutex, MutexGuard};
 use std::cell::RefCell;
+use std::net::{IpAddr, SocketAddr};
 
 
 use time;
@@ -17,14 +18,18 @@ use http::response::Builder;
 use http::header::SET_COOKIE;
 use tokio::prelude::*;
 use horrorshow;
-use cookie::{Cookie, CookieBuilder};
+use cookie::Cookie;
 use bytes::Bytes;
+use url::form_urlencoded;
 
 use router;
-use cookie_store::CookieStore;
+use cookie_store::{CookieStore, Identity, Session};
 use cookie_store::to_cookie;
 use http_server::HttpHandler;
+use proxy::ClientIp;
+use totp;
 
+mod csrf;
 mod handler_login;
 mod views;
 
@@ -34,6 +39,7 @@ enum Route {
     Logout,
     Info,
     Check,
+    Unlock,
 }
 
 fn create_routing_table() -> router::RoutingTable<Route> {
@@ -42,16 +48,21 @@ fn create_routing_table() -> router::RoutingTable<Route> {
     r.insert("/login", Route::Login);
     r.insert("/logout", Route::Logout);
     r.insert("/check", Route::Check);
+    r.insert("/unlock", Route::Unlock);
     r
 }
 
 struct HeaderExtract<'a> {
-    totp_secrets: Vec<&'a str>,
+    totp_secrets: Vec<totp::Secret>,
     cookies: Vec<Cookie<'a>>,
+    client_ip: Option<IpAddr>,
+    user_agent: Option<&'a [u8]>,
 }
 
 static HTTP_HEADER_X_TOTP_SECRET: &'static str = r"X-Totp-Secret";
-static COOKIE_NAME: &'static str = r"totp_cookie";
+static HTTP_HEADER_X_AUTH_USER: &'static str = r"X-Auth-User";
+static HTTP_HEADER_X_AUTH_GROUPS: &'static str = r"X-Auth-Groups";
+static HTTP_HEADER_X_AUTH_SESSION_EXPIRES: &'static str = r"X-Auth-Session-Expires";
 
 #[derive(Clone)]
 pub struct RequestHandler {
@@ -85,6 +96,7 @@ impl HttpHandler<super::ApplicationState> for RequestHandler {
             Ok((Route::Login, rest)) => login(state, &req, rest),
             Ok((Route::Logout, rest)) => logout(state, &req, rest),
             Ok((Route::Check, rest)) => check(state, &req, rest),
+            Ok((Route::Unlock, rest)) => unlock(state, &req, rest),
             Err(error) => match error {
                 router::NoMatchingRoute => Response::builder().set_defaults()
                     .status(StatusCode::NOT_FOUND).body("Resource not found".to_string()).unwrap(),
@@ -99,31 +111,104 @@ impl RequestHandler {
     }
 }
 
-pub(in request_handler) fn is_logged_in(cookies: &Vec<Cookie>, cookie_store: &CookieStore) -> bool {
-    for cookie in cookies {
-        if cookie.name() == COOKIE_NAME {
-            let cookie_value = to_cookie(cookie.value());
-            if cookie_value.is_some() && cookie_store.is_cookie_authenticated(&cookie_value.unwrap()) {
-                return true;
+/// The session of the request together with the value of the cookie referring to it
+fn find_session(header_infos: &HeaderExtract, state: &super::ApplicationState) -> Option<(String, Arc<Session>)> {
+    for cookie in header_infos.cookies.iter() {
+        if cookie.name() == state.cookie_config.name {
+            let session = match state.cookie_signer {
+                Some(ref signer) => signer.verify(cookie.value())
+                    .filter(|session| session.expires >= CookieStore::now_unix_epoch())
+                    .map(Arc::new),
+                None => to_cookie(cookie.value())
+                    .and_then(|key| state.cookie_store.get_authenticated_session(&key)),
+            };
+            if let Some(session) = session {
+                let bound = state.session_binding
+                    .check(&session.binding, header_infos.client_ip, header_infos.user_agent);
+                match bound {
+                    Ok(()) => return Some((cookie.value().to_string(), session)),
+                    Err(reason) => warn!("Reject session of {}: {}",
+                                         session.identity.username.as_ref().map_or("-", |u| u.as_str()),
+                                         reason),
+                }
             }
         }
     }
-    false
+    None
+}
+
+pub(in request_handler) fn authenticated_session(header_infos: &HeaderExtract, state: &super::ApplicationState)
+                                                  -> Option<Arc<Session>> {
+    find_session(header_infos, state).map(|(_, session)| session)
+}
+
+pub(in request_handler) fn is_logged_in(header_infos: &HeaderExtract, state: &super::ApplicationState) -> bool {
+    authenticated_session(header_infos, state).is_some()
+}
+
+pub(in request_handler) fn format_time(ts: u64) -> String {
+    let ts = time::Timespec::new(ts as i64, 0);
+    let tm = time::at_utc(ts);
+    time::strftime("%c", &tm).unwrap_or("</>".to_string())
+}
+
+/// Address of the client as determined by the server from the headers of trusted proxies
+pub(in request_handler) fn client_ip(req: &Request<Bytes>) -> Option<IpAddr> {
+    req.extensions().get::<ClientIp>().map(|client| client.0)
+}
+
+/// Creates the session and returns the cookie referring to it
+pub(in request_handler) fn create_session(header_infos: &HeaderExtract, state: &super::ApplicationState,
+                                           identity: Identity) -> Cookie<'static> {
+    info!("Create session of {}", identity.account);
+    let binding = state.session_binding.bind(header_infos.client_ip, header_infos.user_agent);
+    let session = Session::new(identity, binding, &state.session_lifetime);
+    let expires = session.expires;
+    let value = match state.cookie_signer {
+        Some(ref signer) => signer.sign(&session),
+        None => state.cookie_store.create_authenticated_cookie(session).to_string(),
+    };
+    session_cookie(state, value, expires)
+}
+
+/// Moves the expiry of a used session forward. Returns the session and, if it was
+/// extended, the new cookie value.
+fn extend_session(state: &super::ApplicationState, cookie_value: &str, session: Arc<Session>)
+                  -> (Arc<Session>, Option<String>) {
+    let extended = match session.extended(&state.session_lifetime) {
+        Some(extended) => extended,
+        None => return (session, None),
+    };
+    match state.cookie_signer {
+        Some(ref signer) => {
+            let value = signer.sign(&extended);
+            (Arc::new(extended), Some(value))
+        }
+        None => match to_cookie(cookie_value) {
+            Some(key) => (state.cookie_store.update_session(&key, extended), Some(cookie_value.to_string())),
+            None => (session, None),
+        },
+    }
+}
+
+/// The session cookie, kept by the browser as long as the session is valid
+fn session_cookie(state: &super::ApplicationState, value: String, session_expires: u64) -> Cookie<'static> {
+    let max_age = session_expires.saturating_sub(CookieStore::now_unix_epoch());
+    state.cookie_config.cookie(value, max_age)
 }
 
 fn info<'a>(request_handler: &RequestHandler, state: &super::ApplicationState,
             req: &Request<Bytes>, path_rest: &'a str) -> Response<String> {
-    let ftime = |ts| -> String {
-        let ts = time::Timespec::new(ts, 0);
-        let tm = time::at_utc(ts);
-        time::strftime("%c", &tm).unwrap_or("</>".to_string())
-    };
+    let ftime = |ts: i64| format_time(ts as u64);
     let view = if state.debug {
-        let valid_cookies: Vec<(String, String)> = state.cookie_store.reader
+        let valid_cookies: Vec<(String, String, String)> = state.cookie_store.reader
             .map_into(|k, v|
-                (k.to_string(), ftime(v[0] as i64)));
-        views::info_debug(path_rest, valid_cookies,
-                          state.request_slowdown.load(atomic::Ordering::Acquire))
+                (k.to_string(), ftime(v[0].expires as i64),
+                 v[0].identity.username.clone().unwrap_or_default()));
+        let throttled: Vec<(String, u32, String)> = state.throttle.entries().into_iter()
+            .map(|(key, failures, blocked_until)| (key, failures, ftime(blocked_until as i64)))
+            .collect();
+        views::info_debug(path_rest, valid_cookies, throttled)
     } else {
         views::info(path_rest)
     };
@@ -151,15 +236,48 @@ fn logout<'a>(state: &super::ApplicationState, req: &Request<Bytes>, path_rest:
         Err(message) => return error_handler_internal(message),
     };
 
-    let cookie_delete = CookieBuilder::new(COOKIE_NAME, "")
-        .http_only(true)
-        .path("/")
-        .expires(time::at_utc(time::Timespec::new(0, 0)))
-        .finish();
+    match *req.method() {
+        Method::GET => {
+            let mut response = Response::builder();
+            response.set_defaults();
+            let csrf_token = csrf::token(&header_infos, state, &mut response);
+            return response.body(views::logout_form(csrf_token)).unwrap();
+        }
+        Method::POST => (),
+        _ => return error_handler_internal("Wrong method".to_string()),
+    }
+
+    let mut everywhere = false;
+    let mut csrf_token = None;
+    for (key, val) in form_urlencoded::parse(req.body()) {
+        if key == "everywhere" {
+            everywhere = true
+        } else if key == csrf::FORM_FIELD {
+            csrf_token = Some(val.into_owned())
+        }
+    }
+    if let Err(reason) = csrf::verify(req, &header_infos, state, csrf_token.as_ref().map(|t| t.as_str())) {
+        return csrf::rejected(reason);
+    }
+
+    // the session itself is revoked, a copy of the cookie must not keep working
+    let mut revoked = 0;
+    if let Some((cookie_value, session)) = find_session(&header_infos, state) {
+        if state.cookie_signer.is_some() {
+            warn!("Stateless sessions of {} can not be revoked", session.identity.account);
+        } else if everywhere {
+            revoked = state.cookie_store.remove_account_sessions(&session.identity.account);
+        } else if let Some(key) = to_cookie(&cookie_value) {
+            revoked = state.cookie_store.remove_session(&key) as usize;
+        }
+        info!("Logout of {}, revoked {} sessions", session.identity.account, revoked);
+    }
+
+    let cookie_delete = state.cookie_config.removal();
 
     Response::builder().set_defaults()
         .header(SET_COOKIE, cookie_delete.to_string())
-        .body(views::logout()).unwrap()
+        .body(views::logout(revoked)).unwrap()
 }
 
 fn check<'a>(state: &super::ApplicationState, req: &Request<Bytes>, path_rest: &'a str) -> Response<String> {
@@ -167,9 +285,22 @@ fn check<'a>(state: &super::ApplicationState, req: &Request<Bytes>, path_rest: &
         Ok(infos) => infos,
         Err(message) => return error_handler_internal(message),
     };
-    if is_logged_in(&header_infos.cookies, &state.cookie_store) {
-        Response::builder().set_defaults()
-            .body(Default::default()).unwrap()
+    if let Some((cookie_value, session)) = find_session(&header_infos, state) {
+        let (session, cookie_value) = extend_session(state, &cookie_value, session);
+        let mut response = Response::builder();
+        response.set_defaults()
+            .header(HTTP_HEADER_X_AUTH_SESSION_EXPIRES, session.expires.to_string().as_str());
+        if let Some(cookie_value) = cookie_value {
+            // nginx passes it on to the browser with auth_request_set and add_header
+            response.header(SET_COOKIE, session_cookie(state, cookie_value, session.expires).to_string().as_str());
+        }
+        if let Some(ref username) = session.identity.username {
+            response.header(HTTP_HEADER_X_AUTH_USER, username.as_str());
+        }
+        if !session.identity.groups.is_empty() {
+            response.header(HTTP_HEADER_X_AUTH_GROUPS, session.identity.groups.join(",").as_str());
+        }
+        response.body(Default::default()).unwrap()
     } else {
         Response::builder().set_defaults()
             .status(StatusCode::UNAUTHORIZED)
@@ -177,12 +308,61 @@ fn check<'a>(state: &super::ApplicationState, req: &Request<Bytes>, path_rest: &
     }
 }
 
+/// Lists locked accounts and unlocks them, for users in the admin group only.
+fn unlock<'a>(state: &super::ApplicationState, req: &Request<Bytes>, path_rest: &'a str) -> Response<String> {
+    let header_infos = match parse_header_infos(req) {
+        Ok(infos) => infos,
+        Err(message) => return error_handler_internal(message),
+    };
+    let is_admin = authenticated_session(&header_infos, state)
+        .map(|session| session.identity.groups.contains(&state.admin_group))
+        .unwrap_or(false);
+    if !is_admin {
+        return Response::builder().set_defaults()
+            .status(StatusCode::FORBIDDEN)
+            .body("Only administrators may unlock accounts".to_string()).unwrap();
+    }
+
+    let message = match *req.method() {
+        Method::GET => None,
+        Method::POST => {
+            let mut account = None;
+            let mut csrf_token = None;
+            for (key, val) in form_urlencoded::parse(req.body()) {
+                if key == "account" {
+                    account = Some(val.into_owned())
+                } else if key == csrf::FORM_FIELD {
+                    csrf_token = Some(val.into_owned())
+                }
+            }
+            if let Err(reason) = csrf::verify(req, &header_infos, state, csrf_token.as_ref().map(|t| t.as_str())) {
+                return csrf::rejected(reason);
+            }
+            match account {
+                Some(ref account) if state.lockout.unlock(account) =>
+                    Some(format!("Unlocked {}", account)),
+                Some(ref account) => Some(format!("{} is not locked", account)),
+                None => return error_handler_internal("missing argument 'account'".to_string()),
+            }
+        }
+        _ => return error_handler_internal("Wrong method".to_string()),
+    };
+    let locked = state.lockout.locked_accounts().into_iter()
+        .map(|(account, until)| (account, format_time(until)))
+        .collect();
+    let mut response = Response::builder();
+    response.set_defaults();
+    let csrf_token = csrf::token(&header_infos, state, &mut response);
+    response.body(views::unlock_list(locked, message, csrf_token)).unwrap()
+}
 
 fn parse_header_infos(req: &Request<Bytes>) -> Result<HeaderExtract, String> {
     let mut totp_secrets = Vec::new();
     for header_value in req.headers().get_all(HTTP_HEADER_X_TOTP_SECRET) {
         let value = header_value.to_str().or(Err("Failed to read totp-secret header value"))?;
-        totp_secrets.push(value);
+        let secret = value.parse::<totp::Secret>()
+            .map_err(|e| format!("Failed to parse totp-secret header value: {}", e))?;
+        totp_secrets.push(secret);
     }
 
     let mut cookies = Vec::new();
@@ -197,5 +377,8 @@ fn parse_header_infos(req: &Request<Bytes>) -> Result<HeaderExtract, String> {
         }
     }
 
-    Ok(HeaderExtract { totp_secrets, cookies })
+    // only hashed, so it need not be text
+    let user_agent = req.headers().get(::http::header::USER_AGENT).map(|value| value.as_bytes());
+
+    Ok(HeaderExtract { totp_secrets, cookies, client_ip: client_ip(req), user_agent })
 }
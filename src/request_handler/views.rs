@@ -17,7 +17,8 @@ fn render_base_template(title: &'static str, page_body: Box<RenderBox>) -> Strin
     }).into_string().unwrap()
 }
 
-pub(in super) fn info_debug<'a>(path_rest: &'a str, cookies: Vec<(String, String)>, wait_until: u64) -> String {
+pub(in super) fn info_debug<'a>(path_rest: &'a str, cookies: Vec<(String, String, String)>,
+                                throttled: Vec<(String, u32, String)>) -> String {
     let path = path_rest.to_string();
     render_base_template("Info (debug)", box_html! {
         h1(id = "heading") {
@@ -31,20 +32,34 @@ pub(in super) fn info_debug<'a>(path_rest: &'a str, cookies: Vec<(String, String
             thead {
                 th: "Cookie value";
                 th: "Valid until";
+                th: "User";
             }
             tbody {
-                @ for (name, valid_until) in cookies {
+                @ for (name, valid_until, username) in cookies {
                     tr {
                         td: name;
                         td: valid_until;
+                        td: username;
                     }
                 }
             }
         }
-        h2: "Request Slowdown";
-        p {
-            : "Until: ";
-            : wait_until;
+        h2: "Failed login attempts";
+        table(border="1") {
+            thead {
+                th: "Client or account";
+                th: "Failed attempts";
+                th: "Blocked until";
+            }
+            tbody {
+                @ for (key, failures, blocked_until) in throttled {
+                    tr {
+                        td: key;
+                        td: failures;
+                        td: blocked_until;
+                    }
+                }
+            }
         }
     })
 }
@@ -61,24 +76,42 @@ pub(in super) fn info<'a>(path_rest: &'a str) -> String {
     })
 }
 
-pub(in super) fn login_is_logged_in() -> String {
+pub(in super) fn login_is_logged_in(csrf_token: String) -> String {
     render_base_template("Logged in", box_html! {
         h1(id = "heading") {
             : "Currently logged in"
         }
-        a(href="logout") {
-            : "Go to logout";
-        }
+        : logout_buttons(csrf_token);
     })
 }
 
-pub(in super) fn login_login_form<'a>(redirect: &'a str) -> String {
+fn logout_buttons(csrf_token: String) -> Box<RenderBox> {
+    box_html! {
+        form(method="POST", action="logout") {
+            input(name="csrf", type="hidden", value=&csrf_token);
+            input(name="send", type="submit", value="Logout");
+            input(name="everywhere", type="submit", value="Log out everywhere");
+        }
+    }
+}
+
+pub(in super) fn login_login_form<'a>(redirect: &'a str, ask_username: bool, csrf_token: String) -> String {
     let redirect = redirect.to_string();
     render_base_template("TOTP Login", box_html! {
         h1(id = "heading") {
             : "Login"
         }
         form(method="POST") {
+            @ if ask_username {
+                div {
+                    label(for="username") {
+                            : "Username"
+                    }
+                }
+                div {
+                    input(name="username",id="username",type="text",autocomplete="username",required="");
+                }
+            }
             div {
                 label(for="token") {
                         : "Enter TOTP token"
@@ -87,6 +120,7 @@ pub(in super) fn login_login_form<'a>(redirect: &'a str) -> String {
             div {
                 input(name="token",id="token",type="number",autocomplete="off",required="");
                 input(name="redirect", type="hidden", value=redirect);
+                input(name="csrf", type="hidden", value=csrf_token);
             }
             div {
                 input(name="send",type="submit",value="Submit");
@@ -121,11 +155,86 @@ pub(in super) fn login_auth_fail() -> String {
     })
 }
 
-pub(in super) fn logout() -> String {
+pub(in super) fn login_too_many_attempts(retry_after: u64) -> String {
+    render_base_template("Too many login attempts", box_html! {
+        h1(id = "heading") {
+            : "Too many failed login attempts"
+        }
+        p {
+            : format!("Please wait {} seconds before trying again.", retry_after)
+        }
+        a(href="login") {
+            : "Try again... "
+        }
+    })
+}
+
+pub(in super) fn login_locked(locked_until: String) -> String {
+    render_base_template("Account locked", box_html! {
+        h1(id = "heading") {
+            : "Account locked"
+        }
+        p {
+            : "Too many failed login attempts, the account is locked until ";
+            : locked_until;
+            : " or until an administrator unlocks it.";
+        }
+    })
+}
+
+pub(in super) fn unlock_list(locked: Vec<(String, String)>, message: Option<String>,
+                             csrf_token: String) -> String {
+    render_base_template("Locked accounts", box_html! {
+        h1(id = "heading") {
+            : "Locked accounts"
+        }
+        @ if let Some(message) = message {
+            p: message;
+        }
+        table(border="1") {
+            thead {
+                th: "Account";
+                th: "Locked until";
+                th;
+            }
+            tbody {
+                @ for (account, locked_until) in locked {
+                    tr {
+                        td: &account;
+                        td: locked_until;
+                        td {
+                            form(method="POST") {
+                                input(name="account", type="hidden", value=&account);
+                                input(name="csrf", type="hidden", value=&csrf_token);
+                                input(name="send", type="submit", value="Unlock");
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+pub(in super) fn logout_form(csrf_token: String) -> String {
+    render_base_template("Logout", box_html! {
+        h1(id = "heading") {
+            : "Logout"
+        }
+        : logout_buttons(csrf_token);
+    })
+}
+
+pub(in super) fn logout(revoked: usize) -> String {
     render_base_template("Logout", box_html! {
         h1(id = "heading") {
             : "Logout applied"
         }
+        @ if revoked > 1 {
+            p {
+                : format!("Ended {} sessions", revoked)
+            }
+        }
         a(href="login") {
             : "go to login again..."
         }
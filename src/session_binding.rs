@@ -0,0 +1,99 @@
+use std::net::IpAddr;
+
+use sha2::{Digest, Sha256};
+
+use cookie_store::Binding;
+use proxy::{unmap, Cidr};
+
+/// What a session is bound to at login. A session is only accepted from a client that
+/// still matches.
+#[derive(Clone, Copy, Debug)]
+pub struct BindingPolicy {
+    /// bind to the network of the client address with this IPv4 and IPv6 prefix length
+    pub ip_prefixes: Option<(u8, u8)>,
+    /// bind to the User-Agent header
+    pub user_agent: bool,
+}
+
+impl BindingPolicy {
+    /// The binding to record for a client logging in
+    pub fn bind(&self, client_ip: Option<IpAddr>, user_agent: Option<&[u8]>) -> Binding {
+        Binding {
+            client_ip: self.ip_prefixes.and(client_ip),
+            user_agent: if self.user_agent { Some(hash_user_agent(user_agent)) } else { None },
+        }
+    }
+
+    /// Checks that a client matches the binding of its session, returns the reason if not.
+    pub fn check(&self, binding: &Binding, client_ip: Option<IpAddr>, user_agent: Option<&[u8]>)
+                 -> Result<(), String> {
+        if let Some((prefix_v4, prefix_v6)) = self.ip_prefixes {
+            let bound_ip = binding.client_ip.ok_or("session is not bound to a client address")?;
+            let client_ip = client_ip.ok_or("client address is unknown")?;
+            let prefix = if unmap(bound_ip).is_ipv4() { prefix_v4 } else { prefix_v6 };
+            if !Cidr::new(bound_ip, prefix).contains(&client_ip) {
+                return Err(format!("client address {} does not match {}", client_ip, bound_ip));
+            }
+        }
+        if self.user_agent {
+            let bound_user_agent = binding.user_agent.as_ref()
+                .ok_or("session is not bound to a user agent")?;
+            if *bound_user_agent != hash_user_agent(user_agent) {
+                return Err(format!("user agent {:?} does not match",
+                                   String::from_utf8_lossy(user_agent.unwrap_or(b""))));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Only a hash is stored, the User-Agent itself is of no use after login
+fn hash_user_agent(user_agent: Option<&[u8]>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(user_agent.unwrap_or(b""));
+    format!("{:x}", hasher.result())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn unbound() {
+        let policy = BindingPolicy { ip_prefixes: None, user_agent: false };
+        let binding = policy.bind(ip("192.0.2.1"), Some(b"curl"));
+        assert_eq!(binding, Binding::default());
+        assert!(policy.check(&binding, ip("198.51.100.1"), None).is_ok());
+    }
+
+    #[test]
+    fn bound_to_network() {
+        let policy = BindingPolicy { ip_prefixes: Some((24, 64)), user_agent: false };
+        let binding = policy.bind(ip("192.0.2.1"), Some(b"curl"));
+        assert!(policy.check(&binding, ip("192.0.2.200"), Some(b"wget")).is_ok());
+        assert!(policy.check(&binding, ip("192.0.3.1"), Some(b"curl")).is_err());
+        assert!(policy.check(&binding, None, Some(b"curl")).is_err());
+        let binding = policy.bind(ip("2001:db8::1"), None);
+        assert!(policy.check(&binding, ip("2001:db8::ffff:1"), None).is_ok());
+        assert!(policy.check(&binding, ip("2001:db8:0:1::1"), None).is_err());
+        assert!(policy.check(&Binding::default(), ip("192.0.2.1"), None).is_err());
+    }
+
+    #[test]
+    fn bound_to_user_agent() {
+        let policy = BindingPolicy { ip_prefixes: None, user_agent: true };
+        let binding = policy.bind(ip("192.0.2.1"), Some(b"curl"));
+        assert_eq!(binding.client_ip, None);
+        assert!(policy.check(&binding, ip("198.51.100.1"), Some(b"curl")).is_ok());
+        assert!(policy.check(&binding, ip("192.0.2.1"), Some(b"wget")).is_err());
+        assert!(policy.check(&binding, ip("192.0.2.1"), None).is_err());
+
+        let binding = policy.bind(None, Some(b"Mozilla \xe4"));
+        assert!(policy.check(&binding, None, Some(b"Mozilla \xe4")).is_ok());
+        assert!(policy.check(&binding, None, Some(b"Mozilla \xe5")).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use url::form_urlencoded;
+
+use cookie_store::Session;
+use totp::decode_hex;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Minimum key length in bytes
+const MIN_KEY_LENGTH: usize = 32;
+
+/// Stores the session in the cookie itself instead of the server side `CookieStore`,
+/// such that any instance knowing the key can validate it.
+///
+/// The cookie value is `<hmac>.<session>`, where the session is form-urlencoded and the
+/// hmac is a hex encoded HMAC-SHA256 of it. The session is signed, not encrypted, so the
+/// user name and groups are readable by the client.
+#[derive(Clone)]
+pub struct CookieSigner {
+    /// first key is used for signing, all keys are accepted when verifying
+    keys: Arc<Vec<Vec<u8>>>,
+}
+
+impl CookieSigner {
+    /// Loads hex encoded keys, one per line. The first key signs new cookies, the
+    /// following ones are old keys that are still accepted. Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn load(path: &Path) -> Result<CookieSigner, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let keys = content.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| decode_hex(line).map_err(|_| "key is not valid hex".to_string()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+        CookieSigner::new(keys)
+    }
+
+    fn new(keys: Vec<Vec<u8>>) -> Result<CookieSigner, String> {
+        if keys.is_empty() {
+            return Err("no cookie key configured".to_string());
+        }
+        if keys.iter().any(|key| key.len() < MIN_KEY_LENGTH) {
+            return Err(format!("cookie keys must be at least {} bytes long", MIN_KEY_LENGTH));
+        }
+        Ok(CookieSigner { keys: Arc::new(keys) })
+    }
+
+    pub fn sign(&self, session: &Session) -> String {
+        let payload = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(session.to_record())
+            .finish();
+        let mut mac = Self::mac(&self.keys[0]);
+        mac.input(payload.as_bytes());
+        let signature: String = mac.result().code().iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("{}.{}", signature, payload)
+    }
+
+    /// Returns the session if the signature matches any of the keys. Expiry is not
+    /// checked.
+    pub fn verify(&self, value: &str) -> Option<Session> {
+        let mut parts = value.splitn(2, '.');
+        let signature = decode_hex(parts.next()?).ok()?;
+        let payload = parts.next()?;
+        let valid = self.keys.iter().any(|key| {
+            let mut mac = Self::mac(key);
+            mac.input(payload.as_bytes());
+            mac.verify(&signature).is_ok()
+        });
+        if !valid {
+            return None;
+        }
+        Session::from_record(&form_urlencoded::parse(payload.as_bytes()).into_owned().collect())
+    }
+
+    fn mac(key: &[u8]) -> HmacSha256 {
+        HmacSha256::new_varkey(key).expect("HMAC accepts keys of any size")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cookie_store::{Binding, Identity};
+
+    fn session() -> Session {
+        Session {
+            issued: 10,
+            expires: 20,
+            identity: Identity {
+                account: "alice".to_string(),
+                username: Some("alice".to_string()),
+                groups: vec!["a b".to_string()],
+            },
+            binding: Binding { client_ip: Some("2001:db8::1".parse().unwrap()), user_agent: Some("00ff".to_string()) },
+        }
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let signer = CookieSigner::new(vec![vec![1; 32]]).unwrap();
+        let value = signer.sign(&session());
+        assert_eq!(signer.verify(&value), Some(session()));
+        assert!(!value.contains(|c: char| c == ';' || c == ',' || c == ' ' || c == '"'));
+
+        let tampered = value.replace("alice", "admin");
+        assert_eq!(signer.verify(&tampered), None);
+        assert_eq!(signer.verify("nonsense"), None);
+    }
+
+    #[test]
+    fn verify_with_old_key() {
+        let old_signer = CookieSigner::new(vec![vec![1; 32]]).unwrap();
+        let new_signer = CookieSigner::new(vec![vec![2; 32], vec![1; 32]]).unwrap();
+        let other_signer = CookieSigner::new(vec![vec![3; 32]]).unwrap();
+        let value = old_signer.sign(&session());
+        assert_eq!(new_signer.verify(&value), Some(session()));
+        assert_eq!(other_signer.verify(&value), None);
+    }
+
+    #[test]
+    fn rejects_short_keys() {
+        assert!(CookieSigner::new(vec![vec![1; 16]]).is_err());
+        assert!(CookieSigner::new(vec![]).is_err());
+    }
+}
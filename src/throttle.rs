@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use totp;
+
+/// Delay after the first failed attempt, doubled with every further failure
+const BASE_DELAY_SECS: u64 = 8;
+/// Upper limit of the delay
+const MAX_DELAY_SECS: u64 = 15 * 60;
+/// Entries without failed attempts for this long are forgotten
+const IDLE_EXPIRY_SECS: u64 = 60 * 60;
+
+/// What failed login attempts are counted for
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ThrottleKey {
+    /// address of the client
+    Client(IpAddr),
+    /// user name, or fingerprint of the secrets
+    Account(String),
+}
+
+impl fmt::Display for ThrottleKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ThrottleKey::Client(ref ip) => write!(f, "client {}", ip),
+            ThrottleKey::Account(ref account) => write!(f, "account {}", account),
+        }
+    }
+}
+
+struct Backoff {
+    failures: u32,
+    blocked_until: u64,
+    last_failure: u64,
+}
+
+/// Tracks failed login attempts per client and per account, each with its own
+/// exponential backoff.
+#[derive(Clone)]
+pub struct Throttle {
+    entries: Arc<Mutex<HashMap<ThrottleKey, Backoff>>>,
+}
+
+impl Throttle {
+    pub fn new() -> Throttle {
+        Throttle {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Seconds until any of `keys` allows the next attempt, 0 if no key is blocked.
+    pub fn wait_time(&self, keys: &[ThrottleKey]) -> u64 {
+        let now = totp::now();
+        let entries = self.entries.lock().unwrap();
+        keys.iter()
+            .filter_map(|key| entries.get(key))
+            .map(|backoff| backoff.blocked_until.saturating_sub(now))
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn record_failure(&self, keys: &[ThrottleKey]) {
+        let now = totp::now();
+        let mut entries = self.entries.lock().unwrap();
+        for key in keys {
+            let backoff = entries.entry(key.clone())
+                .or_insert(Backoff { failures: 0, blocked_until: 0, last_failure: 0 });
+            backoff.failures = backoff.failures.saturating_add(1);
+            // the exponent is limited as the delay is capped long before anyway
+            let delay = (BASE_DELAY_SECS << (backoff.failures - 1).min(16)).min(MAX_DELAY_SECS);
+            backoff.blocked_until = now + delay;
+            backoff.last_failure = now;
+            warn!("Failed login attempt {} for {}, blocked for {}s", backoff.failures, key, delay);
+        }
+    }
+
+    pub fn record_success(&self, keys: &[ThrottleKey]) {
+        let mut entries = self.entries.lock().unwrap();
+        for key in keys {
+            entries.remove(key);
+        }
+    }
+
+    /// Forget entries without failed attempts for a while.
+    pub fn clean_idle(&self) {
+        let now = totp::now();
+        self.entries.lock().unwrap().retain(|_, backoff| {
+            backoff.blocked_until > now || backoff.last_failure + IDLE_EXPIRY_SECS > now
+        });
+    }
+
+    /// (key, failed attempts, blocked until) of all tracked keys
+    pub fn entries(&self) -> Vec<(String, u32, u64)> {
+        self.entries.lock().unwrap().iter()
+            .map(|(key, backoff)| (key.to_string(), backoff.failures, backoff.blocked_until))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_is_independent_per_key() {
+        let throttle = Throttle::new();
+        let attacker = ThrottleKey::Client("10.0.0.1".parse().unwrap());
+        let user = ThrottleKey::Client("10.0.0.2".parse().unwrap());
+        let account = ThrottleKey::Account("alice".to_string());
+
+        throttle.record_failure(&[attacker.clone(), account.clone()]);
+        let first = throttle.wait_time(&[attacker.clone()]);
+        assert!(first > 0 && first <= BASE_DELAY_SECS);
+        throttle.record_failure(&[attacker.clone()]);
+        assert!(throttle.wait_time(&[attacker.clone()]) > BASE_DELAY_SECS);
+        assert_eq!(throttle.wait_time(&[user.clone()]), 0);
+        assert!(throttle.wait_time(&[user.clone(), account.clone()]) > 0);
+
+        throttle.record_success(&[account.clone()]);
+        assert_eq!(throttle.wait_time(&[user, account]), 0);
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let throttle = Throttle::new();
+        let key = ThrottleKey::Account("alice".to_string());
+        for _ in 0..100 {
+            throttle.record_failure(&[key.clone()]);
+        }
+        assert!(throttle.wait_time(&[key]) <= MAX_DELAY_SECS);
+    }
+}
@@ -1,30 +1,366 @@
-use oath::totp_custom_time;
+use oath::totp_raw_custom_time;
 use oath::HashType;
+use base32;
+use std::str::FromStr;
 use std::time::{UNIX_EPOCH, SystemTime};
+use url::Url;
 
-pub fn verify(secret: &str, token: &str) -> Result<bool, &'static str> {
-    let time_step = 30;
-    let totp = |time| {
-        totp_custom_time(secret, 6, 0, time_step, time, &HashType::SHA512)
-            .map(|t| {
-                debug!("Generated OTP for probing {} for key {}", t, secret);
-                t
-            })
-            .map(|t| format!("{:06}", t) == *token)
-    };
-    let current_time: u64 = SystemTime::now().duration_since(UNIX_EPOCH)
-        .expect("Earlier than 1970-01-01 00:00:00 UTC").as_secs();
-    if current_time % time_step <= 5 && totp(current_time - 30)? {
-        return Ok(true);
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    SHA1,
+    SHA256,
+    SHA512,
+}
+
+impl Algorithm {
+    fn hash_type(&self) -> HashType {
+        match *self {
+            Algorithm::SHA1 => HashType::SHA1,
+            Algorithm::SHA256 => HashType::SHA256,
+            Algorithm::SHA512 => HashType::SHA512,
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Algorithm, String> {
+        match s.to_uppercase().as_str() {
+            "SHA1" => Ok(Algorithm::SHA1),
+            "SHA256" => Ok(Algorithm::SHA256),
+            "SHA512" => Ok(Algorithm::SHA512),
+            _ => Err(format!("unknown algorithm '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// Time based one-time password (RFC 6238)
+    Totp,
+    /// Counter based one-time password (RFC 4226)
+    Hotp,
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Kind, String> {
+        match s.to_lowercase().as_str() {
+            "totp" => Ok(Kind::Totp),
+            "hotp" => Ok(Kind::Hotp),
+            _ => Err(format!("unknown otp type '{}'", s)),
+        }
+    }
+}
+
+/// Upper bounds of the options, see `Secret`
+const MAX_PERIOD: u64 = 3600;
+const MAX_SKEW: u64 = 10;
+const MAX_WINDOW: u64 = 1000;
+
+/// An OTP secret together with the parameters used to verify tokens against it.
+///
+/// Accepted forms are
+///
+/// * `otpauth://totp/<label>?secret=<base32>[&algorithm=..][&digits=..][&period=..]`
+/// * `otpauth://hotp/<label>?secret=<base32>[&algorithm=..][&digits=..][&counter=..]`
+/// * `base32:<key>[;<option>=<value>]*`
+/// * `[hex:]<key>[;<option>=<value>]*`, e.g. `baadf00d;algorithm=SHA1;digits=8`
+///
+/// Recognized options (and otpauth URI parameters) are `type` (totp, hotp), `algorithm`
+/// (SHA1, SHA256, SHA512) and `digits`. TOTP secrets additionally take `period` (seconds
+/// per time-step) and `skew` (number of accepted time-steps before and after the current
+/// one), HOTP secrets take `counter` (initial counter value) and `window` (number of
+/// counter values looked ahead). Unknown options are an error, other parameters of otpauth
+/// URIs are ignored.
+///
+/// Omitted options default to TOTP, 6 digits, 30s, 1 step, counter 0 and a window of 10.
+/// The period may be at most an hour, the skew 10 steps and the window 1000 counter values,
+/// every accepted value costs an HMAC on each login.
+/// The algorithm defaults to SHA1 for otpauth URIs and HOTP, as specified by the key URI
+/// format and RFC 4226, and to SHA512 otherwise.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Secret {
+    pub key: Vec<u8>,
+    pub kind: Kind,
+    pub algorithm: Algorithm,
+    pub digits: u32,
+    pub period: u64,
+    pub skew: u64,
+    pub counter: u64,
+    pub look_ahead: u64,
+}
+
+impl Secret {
+    fn with_defaults(key: Vec<u8>, kind: Kind, algorithm: Algorithm) -> Secret {
+        Secret { key, kind, algorithm, digits: 6, period: 30, skew: 1, counter: 0, look_ahead: 10 }
     }
 
-    if current_time % time_step >= 25 && totp(current_time + 30)? {
-        return Ok(true);
+    fn set_option(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = |_| format!("invalid value '{}' for totp secret option '{}'", value, name);
+        match name {
+            "type" => self.kind = value.parse()?,
+            "algorithm" => self.algorithm = value.parse()?,
+            "digits" => self.digits = value.parse().map_err(invalid)?,
+            "period" => self.period = value.parse().map_err(invalid)?,
+            "skew" => self.skew = value.parse().map_err(invalid)?,
+            "counter" => self.counter = value.parse().map_err(invalid)?,
+            "window" => self.look_ahead = value.parse().map_err(invalid)?,
+            _ => return Err(format!("unknown totp secret option '{}'", name)),
+        }
+        Ok(())
     }
 
-    if totp(current_time)? {
-        return Ok(true);
+    fn validate(self) -> Result<Secret, String> {
+        if self.key.is_empty() {
+            return Err("empty totp secret".to_string());
+        }
+        if self.digits < 1 || self.digits > 9 {
+            return Err(format!("totp digits must be between 1 and 9, got {}", self.digits));
+        }
+        if self.period == 0 || self.period > MAX_PERIOD {
+            return Err(format!("totp period must be between 1 and {}, got {}", MAX_PERIOD, self.period));
+        }
+        if self.skew > MAX_SKEW {
+            return Err(format!("totp skew must be at most {}, got {}", MAX_SKEW, self.skew));
+        }
+        if self.look_ahead > MAX_WINDOW {
+            return Err(format!("hotp window must be at most {}, got {}", MAX_WINDOW, self.look_ahead));
+        }
+        Ok(self)
     }
 
-    Ok(false)
-}
\ No newline at end of file
+    fn from_otpauth_uri(uri: &str) -> Result<Secret, String> {
+        let uri = Url::parse(uri).map_err(|e| format!("invalid otpauth uri: {}", e))?;
+        let kind = uri.host_str().unwrap_or("").parse()
+            .map_err(|_| format!("unsupported otpauth type '{}'", uri.host_str().unwrap_or("")))?;
+        let mut secret = Secret::with_defaults(Vec::new(), kind, Algorithm::SHA1);
+        for (name, value) in uri.query_pairs() {
+            match name.as_ref() {
+                "secret" => secret.key = decode_base32(&value)?,
+                "algorithm" | "digits" | "period" | "skew" | "counter" | "window" =>
+                    secret.set_option(&name, &value)?,
+                // issuer, type and what authenticator apps add, like image or lock
+                _ => (),
+            }
+        }
+        secret.validate()
+    }
+}
+
+impl FromStr for Secret {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Secret, String> {
+        let s = s.trim();
+        if s.starts_with("otpauth://") {
+            return Secret::from_otpauth_uri(s);
+        }
+
+        let mut parts = s.split(';');
+        let key = parts.next().unwrap_or_default().trim();
+        let key = if key.starts_with("base32:") {
+            decode_base32(&key["base32:".len()..])?
+        } else if key.starts_with("hex:") {
+            decode_hex(&key["hex:".len()..])?
+        } else {
+            decode_hex(key)?
+        };
+        let mut options = Vec::new();
+        for option in parts {
+            let mut kv = option.splitn(2, '=');
+            let name = kv.next().unwrap_or_default().trim();
+            let value = kv.next()
+                .ok_or_else(|| format!("totp secret option '{}' has no value", name))?
+                .trim();
+            options.push((name, value));
+        }
+        // the type determines the default algorithm, so it is applied first
+        let kind = match options.iter().find(|&&(name, _)| name == "type") {
+            Some(&(_, value)) => value.parse()?,
+            None => Kind::Totp,
+        };
+        let algorithm = if kind == Kind::Hotp { Algorithm::SHA1 } else { Algorithm::SHA512 };
+        let mut secret = Secret::with_defaults(key, kind, algorithm);
+        for (name, value) in options {
+            secret.set_option(name, value)?;
+        }
+        secret.validate()
+    }
+}
+
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if hex.len() % 2 != 0 {
+        return Err("hex encoded totp secret has an odd number of digits".to_string());
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2)
+            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            .ok_or_else(|| "totp secret is not valid hex".to_string()))
+        .collect()
+}
+
+fn decode_base32(data: &str) -> Result<Vec<u8>, String> {
+    let data: String = data.chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .collect::<String>()
+        .to_uppercase();
+    base32::decode(base32::Alphabet::RFC4648 { padding: false }, &data)
+        .ok_or_else(|| "totp secret is not valid base32".to_string())
+}
+
+/// Checks `token` against the time-steps within the allowed skew of the time `now`
+/// (seconds since epoch). Steps up to and including `last_used_step` are not considered, so
+/// a token cannot be used twice. Returns the matching time-step.
+pub fn verify_at(secret: &Secret, token: &str, last_used_step: Option<u64>, now: u64) -> Option<u64> {
+    let current_step = now / secret.period;
+    let first_step = current_step.saturating_sub(secret.skew)
+        .max(last_used_step.map(|s| s.saturating_add(1)).unwrap_or(0));
+
+    (first_step..=current_step.saturating_add(secret.skew)).find(|step| matches(secret, token, *step))
+}
+
+/// Checks `token` against the counter values `next_counter` up to `next_counter` plus the
+/// look-ahead window of the secret. Returns the matching counter value.
+pub fn verify_hotp(secret: &Secret, token: &str, next_counter: u64) -> Option<u64> {
+    (next_counter..=next_counter.saturating_add(secret.look_ahead)).find(|counter| matches(secret, token, *counter))
+}
+
+/// Compares `token` to the one-time password for the time-step (TOTP) or counter (HOTP)
+/// `moving_factor`.
+fn matches(secret: &Secret, token: &str, moving_factor: u64) -> bool {
+    // HOTP(K, C) equals TOTP with a time-step of 1 at time C
+    let t = totp_raw_custom_time(&secret.key, secret.digits, 0, 1, moving_factor,
+                                 &secret.algorithm.hash_type());
+    debug!("Generated OTP for probing {}", t);
+    format!("{:01$}", t, secret.digits as usize) == *token
+}
+
+/// Point in time (seconds since epoch) after which a token for `step` is outside of the
+/// accepted window.
+pub fn step_expiry(secret: &Secret, step: u64) -> u64 {
+    step.saturating_add(secret.skew + 1).saturating_mul(secret.period)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .expect("Earlier than 1970-01-01 00:00:00 UTC").as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_plain_key_uses_defaults() {
+        let secret: Secret = "baadf00d".parse().unwrap();
+        assert_eq!(secret.kind, Kind::Totp);
+        assert_eq!(secret.key, vec![0xba, 0xad, 0xf0, 0x0d]);
+        assert_eq!(secret.algorithm, Algorithm::SHA512);
+        assert_eq!(secret.digits, 6);
+        assert_eq!(secret.period, 30);
+        assert_eq!(secret.skew, 1);
+    }
+
+    #[test]
+    fn parse_options() {
+        let secret: Secret = "deadc0de;algorithm=sha1;digits=8;period=60;skew=2".parse().unwrap();
+        assert_eq!(secret.key, vec![0xde, 0xad, 0xc0, 0xde]);
+        assert_eq!(secret.algorithm, Algorithm::SHA1);
+        assert_eq!(secret.digits, 8);
+        assert_eq!(secret.period, 60);
+        assert_eq!(secret.skew, 2);
+    }
+
+    #[test]
+    fn parse_rejects_bad_options() {
+        assert!("deadc0de;digits=0".parse::<Secret>().is_err());
+        assert!("deadc0de;period=0".parse::<Secret>().is_err());
+        assert!("deadc0de;period=3601".parse::<Secret>().is_err());
+        assert!("deadc0de;skew=11".parse::<Secret>().is_err());
+        assert!("deadc0de;skew=18446744073709551615".parse::<Secret>().is_err());
+        assert!("deadc0de;type=hotp;window=1001".parse::<Secret>().is_err());
+        assert!("deadc0de;period=3600;skew=10".parse::<Secret>().is_ok());
+        assert!("deadc0de;algorithm=md5".parse::<Secret>().is_err());
+        assert!("deadc0de;color=blue".parse::<Secret>().is_err());
+        assert!("deadc0de;digits".parse::<Secret>().is_err());
+        assert!(";digits=6".parse::<Secret>().is_err());
+        assert!("deadc0d".parse::<Secret>().is_err());
+        assert!("deadc0xe".parse::<Secret>().is_err());
+        assert!("base32:18".parse::<Secret>().is_err());
+    }
+
+    #[test]
+    fn parse_base32() {
+        let secret: Secret = "base32:32w3 53y=;digits=7".parse().unwrap();
+        assert_eq!(secret.key, vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(secret.algorithm, Algorithm::SHA512);
+        assert_eq!(secret.digits, 7);
+    }
+
+    #[test]
+    fn parse_otpauth_uri() {
+        let secret: Secret = "otpauth://totp/Example:alice@example.com?secret=32W353Y&issuer=Example"
+            .parse().unwrap();
+        assert_eq!(secret.key, vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(secret.algorithm, Algorithm::SHA1);
+        assert_eq!(secret.digits, 6);
+        assert_eq!(secret.period, 30);
+
+        let secret: Secret = "otpauth://totp/x?secret=32W353Y&algorithm=SHA256&digits=8&period=60"
+            .parse().unwrap();
+        assert_eq!(secret.algorithm, Algorithm::SHA256);
+        assert_eq!(secret.digits, 8);
+        assert_eq!(secret.period, 60);
+
+        let secret: Secret = "otpauth://totp/x?secret=32W353Y&image=https%3A%2F%2Fexample.org%2Fa.png&lock=false"
+            .parse().unwrap();
+        assert_eq!(secret.key, vec![0xde, 0xad, 0xbe, 0xef]);
+        assert!("otpauth://totp/x?secret=32W353Y&digits=x".parse::<Secret>().is_err());
+
+        assert!("otpauth://totp/x?algorithm=SHA1".parse::<Secret>().is_err());
+        assert!("otpauth://foo/x?secret=32W353Y".parse::<Secret>().is_err());
+    }
+
+    #[test]
+    fn parse_hotp() {
+        let secret: Secret = "deadc0de;type=hotp;counter=5;window=20".parse().unwrap();
+        assert_eq!(secret.kind, Kind::Hotp);
+        assert_eq!(secret.algorithm, Algorithm::SHA1);
+        assert_eq!(secret.counter, 5);
+        assert_eq!(secret.look_ahead, 20);
+
+        let secret: Secret = "otpauth://hotp/x?secret=32W353Y&counter=3".parse().unwrap();
+        assert_eq!(secret.kind, Kind::Hotp);
+        assert_eq!(secret.counter, 3);
+    }
+
+    #[test]
+    fn verify_hotp_rfc4226_test_vectors() {
+        let secret: Secret = "base32:GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ;type=hotp;window=2"
+            .parse().unwrap();
+        assert_eq!(verify_hotp(&secret, "755224", 0), Some(0));
+        assert_eq!(verify_hotp(&secret, "359152", 0), Some(2));
+        assert_eq!(verify_hotp(&secret, "969429", 0), None);
+        assert_eq!(verify_hotp(&secret, "969429", 1), Some(3));
+        assert_eq!(verify_hotp(&secret, "287082", 2), None);
+    }
+
+    #[test]
+    fn verify_skips_used_steps() {
+        let secret: Secret = "deadc0de;skew=1".parse().unwrap();
+        let now = 1_500_000_000;
+        let step = now / secret.period;
+        let token = format!("{:06}", totp_raw_custom_time(&secret.key, 6, 0, secret.period,
+                                                          step * secret.period,
+                                                          &HashType::SHA512));
+        assert_eq!(verify_at(&secret, &token, None, now), Some(step));
+        assert_eq!(verify_at(&secret, &token, Some(step - 1), now), Some(step));
+        assert_eq!(verify_at(&secret, &token, Some(step), now), None);
+        // still within the skew one step later, but used
+        assert_eq!(verify_at(&secret, &token, None, now + secret.period), Some(step));
+        assert_eq!(verify_at(&secret, &token, Some(step), now + secret.period), None);
+    }
+}
@@ -0,0 +1,179 @@
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+
+use libc;
+
+/// Owner of the socket file given as `user`, `user:group` or `:group`, by name or id
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketOwner {
+    pub uid: Option<libc::uid_t>,
+    pub gid: Option<libc::gid_t>,
+}
+
+impl SocketOwner {
+    fn apply(&self, path: &Path) -> io::Result<()> {
+        // -1 leaves the id unchanged
+        let uid = self.uid.unwrap_or(!0);
+        let gid = self.gid.unwrap_or(!0);
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        if unsafe { libc::chown(path.as_ptr(), uid, gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SocketOwner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let user = parts.next().unwrap_or("");
+        let group = parts.next().unwrap_or("");
+        let owner = SocketOwner {
+            uid: if user.is_empty() { None } else { Some(lookup_user(user)?) },
+            gid: if group.is_empty() { None } else { Some(lookup_group(group)?) },
+        };
+        if owner.uid.is_none() && owner.gid.is_none() {
+            return Err(format!("invalid socket owner '{}', expected user, user:group or :group", s));
+        }
+        Ok(owner)
+    }
+}
+
+fn lookup_user(name: &str) -> Result<libc::uid_t, String> {
+    if let Ok(uid) = name.parse() {
+        return Ok(uid);
+    }
+    let c_name = CString::new(name).map_err(|_| format!("invalid user name '{}'", name))?;
+    let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(format!("unknown user '{}'", name));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let c_name = CString::new(name).map_err(|_| format!("invalid group name '{}'", name))?;
+    let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if group.is_null() {
+        return Err(format!("unknown group '{}'", name));
+    }
+    Ok(unsafe { (*group).gr_gid })
+}
+
+/// File mode of the socket in octal, like `660`
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("invalid socket mode '{}', expected octal permissions like 660", s)),
+    }
+}
+
+/// Listens on a Unix socket at `path` with the given mode and owner. A socket file left
+/// behind by an earlier run is replaced.
+pub fn bind(path: &Path, mode: u32, owner: Option<SocketOwner>) -> io::Result<net::UnixListener> {
+    remove_stale(path)?;
+    // Nobody may connect before the permissions are set. fchmod on the socket does not
+    // change the file on Linux, so the socket is created in a directory only we can
+    // access and moved into place once it is ready.
+    let file_name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is no file", path.display())))?;
+    let mut private_name = OsString::from(".");
+    private_name.push(file_name);
+    private_name.push(format!(".{}", process::id()));
+    let private_dir = path.with_file_name(private_name);
+    fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let result = bind_private(&private_dir.join(file_name), path, mode, owner);
+    if let Err(e) = fs::remove_dir_all(&private_dir) {
+        warn!("Failed to remove {}: {}", private_dir.display(), e);
+    }
+    result
+}
+
+fn bind_private(private_path: &Path, path: &Path, mode: u32, owner: Option<SocketOwner>)
+                -> io::Result<net::UnixListener> {
+    let listener = net::UnixListener::bind(private_path)?;
+    if let Some(owner) = owner {
+        owner.apply(private_path)?;
+    }
+    fs::set_permissions(private_path, fs::Permissions::from_mode(mode))?;
+    fs::rename(private_path, path)?;
+    Ok(listener)
+}
+
+/// Removes the socket file on shutdown
+pub fn remove(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => info!("Removed socket {}", path.display()),
+        Err(e) => warn!("Failed to remove socket {}: {}", path.display(), e),
+    }
+}
+
+/// A socket nobody accepts connections on anymore is removed. A socket still in use or
+/// any other file is left alone.
+fn remove_stale(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                  format!("{} exists and is not a socket", path.display())));
+    }
+    match net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                    format!("{} is in use by another process", path.display()))),
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            info!("Remove stale socket {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert!(parse_mode("1777").is_err());
+        assert!(parse_mode("rw").is_err());
+        assert_eq!("0:0".parse(), Ok(SocketOwner { uid: Some(0), gid: Some(0) }));
+        assert_eq!(":12".parse(), Ok(SocketOwner { uid: None, gid: Some(12) }));
+        assert_eq!("root".parse(), Ok(SocketOwner { uid: Some(0), gid: None }));
+        assert!(":".parse::<SocketOwner>().is_err());
+        assert!("no-such-user-here".parse::<SocketOwner>().is_err());
+    }
+
+    #[test]
+    fn replace_stale_socket() {
+        let path = env::temp_dir().join(format!("nginx-auth-totp-test-{}.sock", process::id()));
+        let listener = bind(&path, 0o600, None).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(bind(&path, 0o600, None).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+        bind(&path, 0o660, None).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+        remove(&path);
+        assert!(!path.exists());
+
+        fs::write(&path, b"").unwrap();
+        assert_eq!(bind(&path, 0o600, None).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use toml;
+
+use totp;
+
+#[derive(Deserialize)]
+struct UsersFile {
+    #[serde(default)]
+    users: HashMap<String, UserEntry>,
+}
+
+#[derive(Deserialize)]
+struct UserEntry {
+    secrets: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+pub struct User {
+    pub name: String,
+    pub secrets: Vec<totp::Secret>,
+    pub groups: Vec<String>,
+}
+
+/// Users and their secrets, loaded from a TOML file like
+///
+/// ```toml
+/// [users.alice]
+/// secrets = ["otpauth://totp/Example:alice?secret=JBSWY3DPEHPK3PXP"]
+/// groups = ["admin"]
+/// ```
+///
+/// Secrets use the same syntax as the `X-Totp-Secret` header.
+pub struct Users {
+    users: HashMap<String, User>,
+}
+
+impl Users {
+    pub fn load(path: &Path) -> Result<Users, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Users::parse(&content)
+            .map_err(|e| format!("failed to parse {}: {}", path.display(), e))
+    }
+
+    fn parse(content: &str) -> Result<Users, String> {
+        let file: UsersFile = toml::from_str(content).map_err(|e| e.to_string())?;
+        let mut users = HashMap::new();
+        for (name, entry) in file.users {
+            let secrets = entry.secrets.iter()
+                .map(|secret| secret.parse::<totp::Secret>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("invalid secret for user '{}': {}", name, e))?;
+            if secrets.is_empty() {
+                return Err(format!("no secrets configured for user '{}'", name));
+            }
+            // names are passed on in X-Auth-User and (comma separated) X-Auth-Groups headers
+            if !is_header_safe(&name) {
+                return Err(format!("user name '{}' contains unsupported characters", name));
+            }
+            if let Some(group) = entry.groups.iter().find(|group| !is_header_safe(group) || group.contains(',')) {
+                return Err(format!("group '{}' of user '{}' contains unsupported characters", group, name));
+            }
+            users.insert(name.clone(), User { name, secrets, groups: entry.groups });
+        }
+        Ok(Users { users })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+}
+
+fn is_header_safe(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b > b' ' && b < 0x7f)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_users() {
+        let users = Users::parse(r#"
+            [users.alice]
+            secrets = ["deadc0de", "base32:32W353Y;type=hotp"]
+
+            [users.bob]
+            secrets = ["otpauth://totp/bob?secret=32W353Y"]
+            groups = ["admin", "dev"]
+        "#).unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users.get("alice").unwrap().secrets.len(), 2);
+        assert_eq!(users.get("bob").unwrap().name, "bob");
+        assert_eq!(users.get("bob").unwrap().groups, vec!["admin", "dev"]);
+        assert!(users.get("alice").unwrap().groups.is_empty());
+        assert!(users.get("carol").is_none());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_secrets() {
+        assert!(Users::parse("[users.alice]\nsecrets = []").is_err());
+        assert!(Users::parse("[users.alice]\nsecrets = [\"xyz\"]").is_err());
+        assert!(Users::parse("[users.alice]").is_err());
+        assert!(Users::parse("[users.\"al ice\"]\nsecrets = [\"deadc0de\"]").is_err());
+        assert!(Users::parse("[users.alice]\nsecrets = [\"deadc0de\"]\ngroups = [\"a,b\"]").is_err());
+    }
+}